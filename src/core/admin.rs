@@ -0,0 +1,402 @@
+use crate::core::config::{AppConfig, CONFIG_FILE, Rule};
+use crate::core::http::MAX_HEADERS;
+use crate::core::http_proxy::read_head;
+use crate::core::route::{RouteEngine, RouteRule, split_host_port};
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
+
+/// 请求体最大长度
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// 管理接口的响应
+struct Response {
+    status: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: &'static str, value: &impl Serialize) -> Self {
+        Self {
+            status,
+            body: serde_json::to_string_pretty(value).unwrap_or_default(),
+        }
+    }
+
+    fn error(status: &'static str, message: impl ToString) -> Self {
+        Self::json(status, &json!({ "error": message.to_string() }))
+    }
+}
+
+/// 启动本地管理接口，规则以在配置中的序号或名称标识
+///
+/// | 方法 | 路径 | 说明 |
+/// |------|------|------|
+/// | GET | /rules | 规则列表 |
+/// | POST | /rules?index=N | 添加规则，默认添加到末尾 |
+/// | GET/PUT/DELETE | /rules/N | 查看、替换、删除规则 |
+/// | POST | /rules/N/enable, /rules/N/disable | 启用、禁用规则 |
+/// | POST | /rules/reorder | 按序号数组调整顺序，如 `[2, 0, 1]` |
+/// | POST | /persist | 将当前规则写入配置文件 |
+///
+/// 请求需要携带 token，请求体必须是 JSON。为防止本机浏览器中的网页通过跨站请求或 DNS rebinding
+/// 修改规则，拒绝带 Origin 的请求，监听本机地址时还拒绝 Host 不是本机的请求
+pub(crate) async fn serve(
+    route_engine: Arc<RouteEngine>,
+    config: Arc<Mutex<AppConfig>>,
+) -> Result<()> {
+    let (listen_addr, token) = {
+        let mut config = config.lock().await;
+        let token = match config.admin.token.clone() {
+            Some(token) => token,
+            None => {
+                let token = format!("{:032x}", rand::random::<u128>());
+                config.admin.token = Some(token.clone());
+                config.save_admin_token(CONFIG_FILE)?;
+                info!("Generated admin token {} in {}", token, CONFIG_FILE);
+                token
+            }
+        };
+        (config.admin.listen_addr.clone(), token)
+    };
+    let listener = TcpListener::bind(&listen_addr).await?;
+    info!("Admin API listening on {}", listen_addr);
+    let access = Arc::new(Access {
+        token,
+        local: listener.local_addr()?.ip().is_loopback(),
+    });
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let engine = route_engine.clone();
+        let config = config.clone();
+        let access = access.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(socket, engine, config, &access).await {
+                error!("Error handling admin client {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(
+    mut client: TcpStream,
+    route_engine: Arc<RouteEngine>,
+    config: Arc<Mutex<AppConfig>>,
+    access: &Access,
+) -> Result<()> {
+    let Some((mut buf, head_len)) = read_head(&mut client).await? else {
+        return Err(anyhow!("Invalid admin request"));
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(&buf[..head_len])?;
+    let (Some(method), Some(target)) = (req.method, req.path) else {
+        return Err(anyhow!("Invalid admin request"));
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+    let content_length = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+        .and_then(|h| {
+            std::str::from_utf8(h.value)
+                .ok()?
+                .trim()
+                .parse::<usize>()
+                .ok()
+        })
+        .unwrap_or(0);
+
+    let response = if let Some(rejected) = access.check(req.headers, content_length) {
+        rejected
+    } else if content_length > MAX_BODY_SIZE {
+        Response::error("413 Payload Too Large", "request body too large")
+    } else {
+        let mut body = buf.split_off(head_len);
+        while body.len() < content_length {
+            if client.read_buf(&mut body).await? == 0 {
+                return Err(anyhow!("Connection closed in request body"));
+            }
+        }
+        body.truncate(content_length);
+        debug!("Admin {} {}", method, target);
+        let mut config = config.lock().await;
+        dispatch(&method, &target, &body, &mut config, &route_engine).await
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.body.len()
+    );
+    client.write_all(head.as_bytes()).await?;
+    client.write_all(response.body.as_bytes()).await?;
+    Ok(())
+}
+
+/// 管理接口的访问控制
+struct Access {
+    token: String,
+    /// 只监听本机地址，要求 Host 也是本机
+    local: bool,
+}
+
+impl Access {
+    /// 检查请求来源、token 及请求体类型，拒绝时返回错误响应
+    fn check(&self, headers: &[httparse::Header], content_length: usize) -> Option<Response> {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .map(|h| String::from_utf8_lossy(h.value))
+        };
+        if header("origin").is_some() {
+            return Some(Response::error(
+                "403 Forbidden",
+                "cross-origin requests are not allowed",
+            ));
+        }
+        if self.local
+            && let Some(host) = header("host")
+            && !is_loopback(split_host_port(host.trim()).0)
+        {
+            return Some(Response::error(
+                "403 Forbidden",
+                "Host must be a loopback address",
+            ));
+        }
+        let token = header("authorization");
+        if token.as_deref().and_then(|t| t.strip_prefix("Bearer ")) != Some(&self.token) {
+            return Some(Response::error(
+                "401 Unauthorized",
+                "missing or invalid token",
+            ));
+        }
+        let content_type = header("content-type");
+        let json = content_type.as_deref().is_some_and(|t| {
+            let mime = t.split(';').next().unwrap_or_default().trim();
+            mime.eq_ignore_ascii_case("application/json")
+        });
+        if (content_length > 0 || content_type.is_some()) && !json {
+            return Some(Response::error(
+                "415 Unsupported Media Type",
+                "request body must be application/json",
+            ));
+        }
+        None
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+async fn dispatch(
+    method: &str,
+    target: &str,
+    body: &[u8],
+    config: &mut AppConfig,
+    route_engine: &RouteEngine,
+) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let mut rules = config.rules.clone();
+    let index = |s: &str, len: usize| match s.parse::<usize>() {
+        Ok(i) => Some(i).filter(|i| *i < len),
+        Err(_) => config
+            .rules
+            .iter()
+            .position(|r| !r.name.is_empty() && r.name == s),
+    };
+
+    match (method, segments.as_slice()) {
+        ("GET", ["rules"]) => return Response::json("200 OK", &config.rules),
+        ("GET", ["rules", i]) => {
+            return match index(i, rules.len()) {
+                Some(i) => Response::json("200 OK", &rules[i]),
+                None => Response::error("404 Not Found", "rule not found"),
+            };
+        }
+        ("POST", ["persist"]) => {
+            return match config.save(CONFIG_FILE) {
+                Ok(()) => {
+                    info!(
+                        "Admin persisted {} rules to {}",
+                        config.rules.len(),
+                        CONFIG_FILE
+                    );
+                    Response::json("200 OK", &json!({ "persisted": config.rules.len() }))
+                }
+                Err(e) => Response::error("500 Internal Server Error", e),
+            };
+        }
+        ("POST", ["rules"]) => {
+            let rule: Rule = match serde_json::from_slice(body) {
+                Ok(rule) => rule,
+                Err(e) => return Response::error("400 Bad Request", e),
+            };
+            let position = query
+                .split('&')
+                .find_map(|p| p.strip_prefix("index="))
+                .and_then(|i| i.parse::<usize>().ok())
+                .unwrap_or(rules.len())
+                .min(rules.len());
+            rules.insert(position, rule);
+        }
+        ("POST", ["rules", "reorder"]) => {
+            let order: Vec<usize> = match serde_json::from_slice(body) {
+                Ok(order) => order,
+                Err(e) => return Response::error("400 Bad Request", e),
+            };
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            if sorted != (0..rules.len()).collect::<Vec<_>>() {
+                return Response::error(
+                    "400 Bad Request",
+                    "order must be a permutation of rule indexes",
+                );
+            }
+            rules = order.iter().map(|i| config.rules[*i].clone()).collect();
+        }
+        ("PUT", ["rules", i]) => {
+            let Some(i) = index(i, rules.len()) else {
+                return Response::error("404 Not Found", "rule not found");
+            };
+            match serde_json::from_slice(body) {
+                Ok(rule) => rules[i] = rule,
+                Err(e) => return Response::error("400 Bad Request", e),
+            }
+        }
+        ("DELETE", ["rules", i]) => {
+            let Some(i) = index(i, rules.len()) else {
+                return Response::error("404 Not Found", "rule not found");
+            };
+            rules.remove(i);
+        }
+        ("POST", ["rules", i, action @ ("enable" | "disable")]) => {
+            let Some(i) = index(i, rules.len()) else {
+                return Response::error("404 Not Found", "rule not found");
+            };
+            rules[i].enabled = *action == "enable";
+        }
+        (_, ["rules"] | ["rules", ..] | ["persist"]) => {
+            return Response::error("405 Method Not Allowed", "method not allowed");
+        }
+        _ => return Response::error("404 Not Found", "not found"),
+    }
+
+    // 校验全部规则后再生效
+    let mut route_rules = Vec::with_capacity(rules.len());
+    for (i, rule) in rules.iter().enumerate() {
+        match RouteRule::try_from(rule) {
+            Ok(rule) => route_rules.push(rule),
+            Err(e) => return Response::error("400 Bad Request", format!("rule #{}: {:#}", i, e)),
+        }
+    }
+    route_engine.update_rules(route_rules).await;
+    config.rules = rules;
+    info!(
+        "Admin {} {} applied, {} rules active",
+        method,
+        path,
+        config.rules.len()
+    );
+    Response::json("200 OK", &config.rules)
+}
+
+#[tokio::test]
+async fn test_access() {
+    use tokio::sync::RwLock;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let engine = Arc::new(RouteEngine {
+        rules: Arc::new(RwLock::new(Vec::new())),
+    });
+    let config = Arc::new(Mutex::new(AppConfig::default()));
+    tokio::spawn(async move {
+        let access = Access {
+            token: "secret".to_string(),
+            local: true,
+        };
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = handle_client(socket, engine.clone(), config.clone(), &access).await;
+        }
+    });
+    let request = |headers: &str| {
+        let body = r#"{"matcher": {"addr": "*"}, "forward": {"addr": "evil.test:80"}}"#;
+        let request = format!(
+            "POST /rules HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+            headers,
+            body.len(),
+            body
+        );
+        async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+    };
+    let auth = "Host: 127.0.0.1\r\nAuthorization: Bearer secret\r\n";
+    // 浏览器不需要预检就能发送的 text/plain 请求
+    let response = request(&format!("{}Content-Type: text/plain\r\n", auth)).await;
+    assert!(response.starts_with("HTTP/1.1 415 "));
+    let response = request(&format!("{}Origin: http://evil.test\r\n", auth)).await;
+    assert!(response.starts_with("HTTP/1.1 403 "));
+    let response = request("Host: evil.test\r\nAuthorization: Bearer secret\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 403 "));
+    let response = request("Host: localhost:1090\r\nContent-Type: application/json\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 401 "));
+    let response = request(&format!("{}Content-Type: application/json\r\n", auth)).await;
+    assert!(response.starts_with("HTTP/1.1 200 "));
+}
+
+#[tokio::test]
+async fn test_dispatch() {
+    use tokio::sync::RwLock;
+
+    let mut config = AppConfig::default();
+    let engine = RouteEngine {
+        rules: Arc::new(RwLock::new(Vec::new())),
+    };
+    let rule = br#"{"matcher": {"addr": "*.dev.local"}, "forward": {"addr": "127.0.0.1:8080"}}"#;
+    let response = dispatch("POST", "/rules?index=0", rule, &mut config, &engine).await;
+    assert_eq!(response.status, "200 OK");
+    assert_eq!(config.rules[0].matcher.addr, "*.dev.local");
+    assert_eq!(engine.rules.read().await.len(), 2);
+
+    let response = dispatch("POST", "/rules/example/disable", b"", &mut config, &engine).await;
+    assert_eq!(response.status, "200 OK");
+    assert!(!engine.rules.read().await[1].enabled);
+    let response = dispatch("POST", "/rules/0/disable", b"", &mut config, &engine).await;
+    assert_eq!(response.status, "200 OK");
+    assert!(!engine.rules.read().await[0].enabled);
+
+    let response = dispatch("POST", "/rules/reorder", b"[1, 0]", &mut config, &engine).await;
+    assert_eq!(response.status, "200 OK");
+    assert_eq!(config.rules[1].matcher.addr, "*.dev.local");
+
+    // 无效的规则不生效
+    let invalid = br#"{"matcher": {"addr": "*", "ports": "x"}, "forward": {"addr": "a:1"}}"#;
+    let response = dispatch("PUT", "/rules/0", invalid, &mut config, &engine).await;
+    assert_eq!(response.status, "400 Bad Request");
+    assert_eq!(config.rules[0].matcher.addr, "192.168.120.177:81");
+
+    let response = dispatch("POST", "/rules/reorder", b"[0, 0]", &mut config, &engine).await;
+    assert_eq!(response.status, "400 Bad Request");
+    let response = dispatch("DELETE", "/rules/5", b"", &mut config, &engine).await;
+    assert_eq!(response.status, "404 Not Found");
+    let response = dispatch("DELETE", "/rules/1", b"", &mut config, &engine).await;
+    assert_eq!(response.status, "200 OK");
+    assert_eq!(engine.rules.read().await.len(), 1);
+}
@@ -0,0 +1,103 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::AsyncWrite;
+
+/// 复制转发的数据，超过最大长度时丢弃
+#[derive(Debug)]
+pub(crate) struct Capture {
+    data: Vec<u8>,
+    limit: usize,
+    overflow: bool,
+    /// 写入的总长度
+    len: usize,
+}
+
+impl Capture {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            data: Vec::new(),
+            limit,
+            overflow: false,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn append(&mut self, data: &[u8]) {
+        self.len += data.len();
+        if self.overflow {
+            return;
+        }
+        if self.data.len() + data.len() > self.limit {
+            self.overflow = true;
+            self.data = Vec::new();
+            return;
+        }
+        self.data.extend_from_slice(data);
+    }
+
+    /// 是否超过最大长度
+    pub(crate) fn overflow(&self) -> bool {
+        self.overflow
+    }
+
+    /// 写入的总长度，包括超过最大长度后丢弃的数据
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// 复制的数据，超过最大长度时返回 None
+    pub(crate) fn data(&self) -> Option<&[u8]> {
+        (!self.overflow).then_some(self.data.as_slice())
+    }
+}
+
+/// 写入目标的同时复制到各个 Capture
+pub(crate) struct Tee<'a, W> {
+    dst: &'a mut W,
+    captures: Vec<&'a mut Capture>,
+}
+
+impl<'a, W> Tee<'a, W> {
+    pub(crate) fn new(dst: &'a mut W, captures: impl IntoIterator<Item = &'a mut Capture>) -> Self {
+        Self {
+            dst,
+            captures: captures.into_iter().collect(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Tee<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut *self.dst).poll_write(cx, buf))?;
+        for capture in self.captures.iter_mut() {
+            capture.append(&buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.dst).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.dst).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_tee() {
+    use tokio::io::AsyncWriteExt;
+    let (mut small, mut large) = (Capture::new(4), Capture::new(16));
+    let mut dst = Vec::new();
+    let mut tee = Tee::new(&mut dst, [&mut small, &mut large]);
+    tee.write_all(b"hello").await.unwrap();
+    assert_eq!(dst, b"hello");
+    assert!(small.overflow() && small.data().is_none());
+    assert_eq!(small.len(), 5);
+    assert_eq!(large.data().unwrap(), b"hello");
+}
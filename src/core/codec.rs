@@ -0,0 +1,496 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// SOCKS 协议版本
+pub(crate) const SOCKS_VERSION: u8 = 0x05;
+/// SOCKS4/4a 协议版本
+pub(crate) const SOCKS4_VERSION: u8 = 0x04;
+/// SOCKS4 USERID 及 SOCKS4a 域名的最大长度
+const SOCKS4_MAX_FIELD_LEN: usize = 255;
+/// 用户名/密码子协商版本
+pub(crate) const AUTH_VERSION: u8 = 0x01;
+
+/// 帧解码错误
+#[derive(Debug, Error)]
+pub(crate) enum FrameError {
+    #[error("Unsupported SOCKS version: {0:#04x}")]
+    UnsupportedVersion(u8),
+    #[error("Unsupported authentication version: {0:#04x}")]
+    UnsupportedAuthVersion(u8),
+    #[error("Invalid SOCKS greeting: no authentication methods")]
+    NoMethods,
+    #[error("Unsupported address type: {0:#04x}")]
+    UnsupportedAddressType(u8),
+    #[error("Invalid domain name")]
+    InvalidDomain,
+    #[error("Field exceeds {SOCKS4_MAX_FIELD_LEN} bytes")]
+    FieldTooLong,
+    #[error("Connection closed before frame completed")]
+    UnexpectedEof,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// 可从字节流中增量解码的帧
+pub(crate) trait Decode: Sized {
+    /// 从缓冲区头部解码一帧，返回帧及其占用的字节数，数据不完整时返回 None
+    fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, FrameError>;
+}
+
+/// 目标地址
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Ip(addr) => write!(f, "{}", addr),
+            Address::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Ip(addr)
+    }
+}
+
+impl Address {
+    /// 编码地址 ATYP ADDR PORT
+    pub(crate) fn encode(&self, buf: &mut impl BufMut) {
+        match self {
+            Address::Ip(SocketAddr::V4(v4)) => {
+                buf.put_u8(0x01);
+                buf.put_slice(&v4.ip().octets());
+                buf.put_u16(v4.port());
+            }
+            Address::Ip(SocketAddr::V6(v6)) => {
+                buf.put_u8(0x04);
+                buf.put_slice(&v6.ip().octets());
+                buf.put_u16(v6.port());
+            }
+            Address::Domain(domain, port) => {
+                buf.put_u8(0x03);
+                buf.put_u8(domain.len() as u8);
+                buf.put_slice(domain.as_bytes());
+                buf.put_u16(*port);
+            }
+        }
+    }
+}
+
+impl Decode for Address {
+    fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, FrameError> {
+        let Some(&atyp) = buf.first() else {
+            return Ok(None);
+        };
+        let len = match atyp {
+            0x01 => 1 + 4 + 2,
+            0x03 => match buf.get(1) {
+                Some(0) => return Err(FrameError::InvalidDomain),
+                Some(&n) => 1 + 1 + n as usize + 2,
+                None => return Ok(None),
+            },
+            0x04 => 1 + 16 + 2,
+            _ => return Err(FrameError::UnsupportedAddressType(atyp)),
+        };
+        let Some(data) = buf.get(..len) else {
+            return Ok(None);
+        };
+        let port = u16::from_be_bytes([data[len - 2], data[len - 1]]);
+        let address = match atyp {
+            0x01 => {
+                let ip = Ipv4Addr::new(data[1], data[2], data[3], data[4]);
+                Address::Ip(SocketAddr::from((ip, port)))
+            }
+            0x03 => {
+                let domain = std::str::from_utf8(&data[2..len - 2])
+                    .map_err(|_| FrameError::InvalidDomain)?;
+                Address::Domain(domain.to_string(), port)
+            }
+            _ => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&data[1..17]);
+                Address::Ip(SocketAddr::from((Ipv6Addr::from(octets), port)))
+            }
+        };
+        Ok(Some((address, len)))
+    }
+}
+
+/// 认证协商请求
+///
+/// +----+----------+----------+
+/// |VER | NMETHODS | METHODS  |
+/// +----+----------+----------+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Greeting {
+    pub(crate) methods: Vec<u8>,
+}
+
+impl Decode for Greeting {
+    fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, FrameError> {
+        let Some(&version) = buf.first() else {
+            return Ok(None);
+        };
+        if version != SOCKS_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let count = match buf.get(1) {
+            Some(0) => return Err(FrameError::NoMethods),
+            Some(&n) => n as usize,
+            None => return Ok(None),
+        };
+        Ok(buf.get(2..2 + count).map(|methods| {
+            let greeting = Greeting {
+                methods: methods.to_vec(),
+            };
+            (greeting, 2 + count)
+        }))
+    }
+}
+
+/// 用户名/密码认证请求 RFC 1929
+///
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+#[derive(Debug, PartialEq)]
+pub(crate) struct AuthRequest {
+    pub(crate) username: Vec<u8>,
+    pub(crate) password: Vec<u8>,
+}
+
+impl Decode for AuthRequest {
+    fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, FrameError> {
+        let Some(&version) = buf.first() else {
+            return Ok(None);
+        };
+        if version != AUTH_VERSION {
+            return Err(FrameError::UnsupportedAuthVersion(version));
+        }
+        let Some(&username_len) = buf.get(1) else {
+            return Ok(None);
+        };
+        let username_end = 2 + username_len as usize;
+        let Some(&password_len) = buf.get(username_end) else {
+            return Ok(None);
+        };
+        let password_end = username_end + 1 + password_len as usize;
+        let Some(password) = buf.get(username_end + 1..password_end) else {
+            return Ok(None);
+        };
+        let request = AuthRequest {
+            username: buf[2..username_end].to_vec(),
+            password: password.to_vec(),
+        };
+        Ok(Some((request, password_end)))
+    }
+}
+
+/// 连接请求
+///
+/// +----+-----+-------+------+----------+----------+
+/// |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+/// +----+-----+-------+------+----------+----------+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Request {
+    pub(crate) command: u8,
+    pub(crate) address: Address,
+}
+
+impl Decode for Request {
+    fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, FrameError> {
+        let Some(&version) = buf.first() else {
+            return Ok(None);
+        };
+        if version != SOCKS_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let Some(rest) = buf.get(3..) else {
+            return Ok(None);
+        };
+        Ok(Address::decode(rest)?.map(|(address, len)| {
+            let request = Request {
+                command: buf[1],
+                address,
+            };
+            (request, 3 + len)
+        }))
+    }
+}
+
+/// SOCKS4/4a 连接请求
+///
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+/// | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+///
+/// SOCKS4a 中 DSTIP 为 0.0.0.x（x非0）时，USERID 后紧跟以NULL结尾的域名
+#[derive(Debug, PartialEq)]
+pub(crate) struct Socks4Request {
+    pub(crate) command: u8,
+    pub(crate) address: Address,
+    pub(crate) user_id: Vec<u8>,
+}
+
+impl Decode for Socks4Request {
+    fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, FrameError> {
+        let Some(&version) = buf.first() else {
+            return Ok(None);
+        };
+        if version != SOCKS4_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let Some(header) = buf.get(..8) else {
+            return Ok(None);
+        };
+        let port = u16::from_be_bytes([header[2], header[3]]);
+        let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+        let Some((user_id, user_id_end)) = read_null_terminated(buf, 8)? else {
+            return Ok(None);
+        };
+
+        let octets = ip.octets();
+        let is_socks4a = octets[..3] == [0, 0, 0] && octets[3] != 0;
+        let (address, len) = if is_socks4a {
+            let Some((domain, domain_end)) = read_null_terminated(buf, user_id_end)? else {
+                return Ok(None);
+            };
+            if domain.is_empty() {
+                return Err(FrameError::InvalidDomain);
+            }
+            let domain = std::str::from_utf8(domain).map_err(|_| FrameError::InvalidDomain)?;
+            (Address::Domain(domain.to_string(), port), domain_end)
+        } else {
+            (Address::Ip(SocketAddr::from((ip, port))), user_id_end)
+        };
+        let request = Socks4Request {
+            command: header[1],
+            address,
+            user_id: user_id.to_vec(),
+        };
+        Ok(Some((request, len)))
+    }
+}
+
+/// 读取从 start 开始以NULL结尾的字段，返回字段内容及NULL之后的位置
+fn read_null_terminated(buf: &[u8], start: usize) -> Result<Option<(&[u8], usize)>, FrameError> {
+    let data = buf.get(start..).unwrap_or_default();
+    match data.iter().position(|&b| b == 0) {
+        Some(n) if n > SOCKS4_MAX_FIELD_LEN => Err(FrameError::FieldTooLong),
+        Some(n) => Ok(Some((&data[..n], start + n + 1))),
+        None if data.len() > SOCKS4_MAX_FIELD_LEN => Err(FrameError::FieldTooLong),
+        None => Ok(None),
+    }
+}
+
+/// 帧读取器，从连接中读取数据直到收到完整的帧
+pub(crate) struct FrameReader {
+    buf: BytesMut,
+}
+
+impl FrameReader {
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: BytesMut::with_capacity(512),
+        }
+    }
+
+    pub(crate) async fn read<T, R>(&mut self, reader: &mut R) -> Result<T, FrameError>
+    where
+        T: Decode,
+        R: AsyncRead + Unpin,
+    {
+        loop {
+            if let Some((frame, len)) = T::decode(&self.buf)? {
+                self.buf.advance(len);
+                return Ok(frame);
+            }
+            self.buf.reserve(512);
+            if reader.read_buf(&mut self.buf).await? == 0 {
+                return Err(FrameError::UnexpectedEof);
+            }
+        }
+    }
+
+    /// 读取协议版本，不消耗数据
+    pub(crate) async fn peek_version<R>(&mut self, reader: &mut R) -> Result<u8, FrameError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        while self.buf.is_empty() {
+            self.buf.reserve(512);
+            if reader.read_buf(&mut self.buf).await? == 0 {
+                return Err(FrameError::UnexpectedEof);
+            }
+        }
+        Ok(self.buf[0])
+    }
+
+    /// 握手结束后已读取但未处理的数据
+    pub(crate) fn into_remaining(self) -> BytesMut {
+        self.buf
+    }
+}
+
+/// 简单的伪随机数，用于生成模糊测试数据
+#[cfg(test)]
+fn pseudo_random(seed: &mut u64) -> u8 {
+    *seed = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    (*seed >> 33) as u8
+}
+
+#[test]
+fn test_decode_fragmented() {
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, 11];
+    request.extend_from_slice(b"example.com");
+    request.extend_from_slice(&[0x01, 0xBB]);
+    for len in 0..request.len() {
+        assert!(Request::decode(&request[..len]).unwrap().is_none());
+    }
+    let (frame, len) = Request::decode(&request).unwrap().unwrap();
+    assert_eq!(len, request.len());
+    assert_eq!(frame.command, 0x01);
+    assert_eq!(frame.address.to_string(), "example.com:443");
+
+    let greeting = [0x05, 0x02, 0x00, 0x02];
+    for len in 0..greeting.len() {
+        assert!(Greeting::decode(&greeting[..len]).unwrap().is_none());
+    }
+    let (frame, _) = Greeting::decode(&greeting).unwrap().unwrap();
+    assert_eq!(frame.methods, vec![0x00, 0x02]);
+
+    let auth = [0x01, 0x03, b'd', b'e', b'v', 0x02, b'p', b'w'];
+    for len in 0..auth.len() {
+        assert!(AuthRequest::decode(&auth[..len]).unwrap().is_none());
+    }
+    let (frame, len) = AuthRequest::decode(&auth).unwrap().unwrap();
+    assert_eq!(
+        (frame.username.as_slice(), frame.password.as_slice(), len),
+        (b"dev".as_ref(), b"pw".as_ref(), 8)
+    );
+}
+
+#[test]
+fn test_decode_socks4() {
+    let request = [0x04, 0x01, 0x00, 0x50, 10, 0, 0, 1, b'u', 0x00];
+    for len in 0..request.len() {
+        assert!(Socks4Request::decode(&request[..len]).unwrap().is_none());
+    }
+    let (frame, len) = Socks4Request::decode(&request).unwrap().unwrap();
+    assert_eq!(len, request.len());
+    assert_eq!(frame.address.to_string(), "10.0.0.1:80");
+    assert_eq!(frame.user_id, b"u".to_vec());
+
+    let mut request = vec![0x04, 0x01, 0x01, 0xBB, 0, 0, 0, 1, 0x00];
+    request.extend_from_slice(b"example.com\0");
+    for len in 0..request.len() {
+        assert!(Socks4Request::decode(&request[..len]).unwrap().is_none());
+    }
+    let (frame, len) = Socks4Request::decode(&request).unwrap().unwrap();
+    assert_eq!(len, request.len());
+    assert_eq!(frame.address.to_string(), "example.com:443");
+
+    let mut too_long = vec![0x04, 0x01, 0x00, 0x50, 10, 0, 0, 1];
+    too_long.extend_from_slice(&[b'a'; 300]);
+    assert!(matches!(
+        Socks4Request::decode(&too_long),
+        Err(FrameError::FieldTooLong)
+    ));
+    let empty_domain = [0x04, 0x01, 0x00, 0x50, 0, 0, 0, 1, 0x00, 0x00];
+    assert!(matches!(
+        Socks4Request::decode(&empty_domain),
+        Err(FrameError::InvalidDomain)
+    ));
+}
+
+#[test]
+fn test_decode_invalid() {
+    assert!(matches!(
+        Greeting::decode(&[0x04, 0x01]),
+        Err(FrameError::UnsupportedVersion(0x04))
+    ));
+    assert!(matches!(
+        Greeting::decode(&[0x05, 0x00]),
+        Err(FrameError::NoMethods)
+    ));
+    assert!(matches!(
+        Request::decode(&[0x05, 0x01, 0x00, 0x05, 0, 0]),
+        Err(FrameError::UnsupportedAddressType(0x05))
+    ));
+    assert!(matches!(
+        Request::decode(&[0x05, 0x01, 0x00, 0x03, 0x00]),
+        Err(FrameError::InvalidDomain)
+    ));
+    assert!(matches!(
+        Request::decode(&[0x05, 0x01, 0x00, 0x03, 0x01, 0xFF, 0x00, 0x50]),
+        Err(FrameError::InvalidDomain)
+    ));
+    assert!(matches!(
+        AuthRequest::decode(&[0x05]),
+        Err(FrameError::UnsupportedAuthVersion(0x05))
+    ));
+}
+
+#[test]
+fn test_decode_fuzzed() {
+    let mut seed = 0x5EED;
+    for _ in 0..20000 {
+        let len = pseudo_random(&mut seed) as usize % 64;
+        let mut data: Vec<u8> = (0..len).map(|_| pseudo_random(&mut seed)).collect();
+        // 提高进入深层解析分支的概率
+        if len > 0 {
+            data[0] = [0x05, 0x04, 0x01, pseudo_random(&mut seed)][len % 4];
+        }
+        if let Ok(Some((_, n))) = Greeting::decode(&data) {
+            assert!(n <= data.len());
+        }
+        if let Ok(Some((_, n))) = AuthRequest::decode(&data) {
+            assert!(n <= data.len());
+        }
+        if let Ok(Some((_, n))) = Request::decode(&data) {
+            assert!(n <= data.len());
+        }
+        if let Ok(Some((_, n))) = Socks4Request::decode(&data) {
+            assert!(n <= data.len());
+        }
+        if let Ok(Some((_, n))) = Address::decode(&data) {
+            assert!(n <= data.len());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_frame_reader_partial_reads() {
+    use tokio::io::AsyncWriteExt;
+
+    let (mut client, mut server) = tokio::io::duplex(64);
+    let mut data = vec![0x05, 0x01, 0x00];
+    data.extend_from_slice(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50]);
+    data.extend_from_slice(b"GET /");
+    tokio::spawn(async move {
+        for b in data {
+            client.write_all(&[b]).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+    });
+
+    let mut reader = FrameReader::new();
+    let greeting: Greeting = reader.read(&mut server).await.unwrap();
+    assert_eq!(greeting.methods, vec![0x00]);
+    let request: Request = reader.read(&mut server).await.unwrap();
+    assert_eq!(request.address.to_string(), "127.0.0.1:80");
+    let result: Result<Request, _> = reader.read(&mut server).await;
+    assert!(matches!(result, Err(FrameError::UnsupportedVersion(b'G'))));
+}
@@ -1,46 +1,93 @@
 use config::{Config, ConfigError, File};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io::Write;
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default)]
 pub struct AppConfig {
     /// 规则列表
     pub rules: Vec<Rule>,
+    /// 监听地址 host:port，IPv6 使用 [::1]:1080 格式
     pub listen_addr: String,
+    /// SOCKS5 用户名/密码认证（RFC 1929），为空时不需要认证
+    pub users: Vec<User>,
+    /// 管理接口
+    pub admin: Admin,
 }
 impl Default for AppConfig {
     fn default() -> Self {
         let mut config = Self {
             rules: Vec::new(),
             listen_addr: "127.0.0.1:1080".to_string(),
+            users: Vec::new(),
+            admin: Admin::default(),
         };
         //默认示例
         config.rules.push(Rule {
+            name: "example".to_string(),
             matcher: Host {
                 addr: "192.168.120.177:81".to_string(),
                 path_prefix: "/api".to_string(),
+                ..Default::default()
             },
-            forward: Host {
+            forward: Forward {
                 addr: "127.0.0.1:8686".to_string(),
                 path_prefix: "".to_string(),
+                ..Default::default()
             },
+            ..Default::default()
         });
 
         config
     }
 }
+/// 配置文件路径
+pub(crate) const CONFIG_FILE: &str = "config.toml";
+
 impl AppConfig {
     pub(crate) fn init() -> Result<Self, ConfigError> {
-        let conf_file_path = "config.toml";
+        let conf_file_path = CONFIG_FILE;
         let result = std::fs::File::open(conf_file_path);
         if result.is_err() {
             let mut file = std::fs::File::create(conf_file_path).unwrap();
             let config = AppConfig::default();
             let ac = toml::to_string(&config).unwrap_or("".into());
-            file.write(ac.as_ref()).unwrap();
+            file.write_all(ac.as_ref()).unwrap();
             file.flush().unwrap();
         }
 
+        Self::load(conf_file_path)
+    }
+
+    /// 将规则保存到配置文件，只替换 rules，保留其他设置及注释
+    pub(crate) fn save(&self, conf_file_path: &str) -> anyhow::Result<()> {
+        #[derive(Serialize)]
+        struct Rules<'a> {
+            rules: &'a [Rule],
+        }
+        let rules: toml_edit::DocumentMut =
+            toml::to_string(&Rules { rules: &self.rules })?.parse()?;
+        edit_file(conf_file_path, |document| {
+            document["rules"] = rules["rules"].clone();
+            // 与前面的设置之间保留空行
+            if let Some(tables) = document["rules"].as_array_of_tables_mut()
+                && let Some(first) = tables.get_mut(0)
+            {
+                first.decor_mut().set_prefix("\n");
+            }
+        })
+    }
+
+    /// 将管理接口的 token 保存到配置文件
+    pub(crate) fn save_admin_token(&self, conf_file_path: &str) -> anyhow::Result<()> {
+        let token = self.admin.token.clone().unwrap_or_default();
+        edit_file(conf_file_path, |document| {
+            document["admin"]["token"] = toml_edit::value(token);
+        })
+    }
+
+    /// 读取配置文件
+    pub(crate) fn load(conf_file_path: &str) -> Result<Self, ConfigError> {
         let c = Config::builder()
             .add_source(File::with_name(conf_file_path))
             .build()?;
@@ -49,18 +96,608 @@ impl AppConfig {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// 修改配置文件中的部分设置，保留其他内容及注释
+fn edit_file(
+    conf_file_path: &str,
+    edit: impl FnOnce(&mut toml_edit::DocumentMut),
+) -> anyhow::Result<()> {
+    let mut document: toml_edit::DocumentMut = match std::fs::read_to_string(conf_file_path) {
+        Ok(content) => content.parse()?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+        Err(e) => return Err(e.into()),
+    };
+    edit(&mut document);
+    // 先写临时文件再替换，避免写入过程中被重新加载
+    let tmp_path = format!("{}.tmp", conf_file_path);
+    std::fs::write(&tmp_path, document.to_string())?;
+    std::fs::rename(&tmp_path, conf_file_path)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Rule {
+    /// 规则名称，用于日志、管理接口及重新加载时识别规则，为空时使用匹配地址
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// 规则说明
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// 是否启用，禁用的规则不参与匹配
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub enabled: bool,
+    /// 优先级，越大越优先，相同时更具体的规则优先（精确域名、更长的路径前缀、更多的条件）
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    /// 匹配的请求方法，为空时匹配所有方法
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
     /// 匹配配置
     pub matcher: Host,
     /// 转发配置
-    pub forward: Host,
+    pub forward: Forward,
+    /// 请求头条件，名称忽略大小写
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<Predicate>,
+    /// 查询参数条件，匹配未解码的原始值
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub query: Vec<Predicate>,
+    /// Cookie 条件
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cookies: Vec<Predicate>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl Default for Rule {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            description: None,
+            enabled: true,
+            priority: 0,
+            methods: Vec::new(),
+            matcher: Host::default(),
+            forward: Forward::default(),
+            headers: Vec::new(),
+            query: Vec::new(),
+            cookies: Vec::new(),
+        }
+    }
+}
+
+/// 请求头、查询参数或 Cookie 的匹配条件，equals、contains、regex、present 只能设置一个
+///
+/// `{ name = "X-Debug", equals = "me" }`、`{ name = "session", present = false }`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Predicate {
+    /// 名称
+    pub name: String,
+    /// 值等于
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equals: Option<String>,
+    /// 值包含
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contains: Option<String>,
+    /// 值匹配正则
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    /// true 时要求存在，false 时要求不存在
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub present: Option<bool>,
+}
+
+/// 匹配配置
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Host {
-    /// host:port
+    /// host:port，支持 * 和 ? 通配符，如 `*.dev.local`、`10.0.*.*:8080`
+    #[serde(default)]
     pub addr: String,
     /// 路径前缀
+    #[serde(default)]
     pub path_prefix: String,
+    /// 目标IP所在的 CIDR 范围，如 `10.0.0.0/8`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cidr: Option<String>,
+    /// 目标端口范围，如 `8000-8999` 或 `8080`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<String>,
+    /// 路径正则，如 `^/v1/(.*)`，转发配置的路径前缀中可用 `$1` 引用捕获组
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_regex: Option<String>,
+}
+
+/// 转发配置
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Forward {
+    /// host:port，模拟响应时可以为空
+    #[serde(default)]
+    pub addr: String,
+    /// 路径前缀
+    #[serde(default)]
+    pub path_prefix: String,
+    /// 自动将匹配的路径前缀替换为转发的路径前缀
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub rewrite: bool,
+    /// 转发地址连接失败时使用原始地址
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub connect_fail_use_original_host: bool,
+    /// 转发时将 Host 请求头改为转发地址
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub rewrite_host: bool,
+    /// 请求头改写
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub request_headers: HeaderRules,
+    /// 响应头改写
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub response_headers: HeaderRules,
+    /// 转发地址连接失败时的处理
+    #[serde(default, skip_serializing_if = "ConnectFailure::is_default")]
+    pub on_connect_fail: ConnectFailure,
+    /// 其他上游地址，与 addr 一起按负载均衡策略转发
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstreams: Vec<String>,
+    /// 负载均衡策略
+    #[serde(default, skip_serializing_if = "Balance::is_default")]
+    pub balance: Balance,
+    /// 一致性哈希使用的请求头，为空时使用客户端IP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_header: Option<String>,
+    /// 主动健康检查，不健康的上游不参与负载均衡
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    /// 将请求复制一份异步发送到镜像地址
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<Mirror>,
+    /// 响应改写
+    #[serde(default, skip_serializing_if = "ResponseRewrite::is_empty")]
+    pub response: ResponseRewrite,
+    /// 直接返回模拟响应，不连接转发地址
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mock: Option<Mock>,
+    /// 录制转发的请求及响应，或回放录制的响应
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record: Option<Record>,
+    /// 故障注入
+    #[serde(default, skip_serializing_if = "Faults::is_empty")]
+    pub faults: Faults,
+    /// 解密匹配主机的 TLS 连接，请求按规则改写及转发
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mitm: Option<Mitm>,
+}
+
+impl Default for Forward {
+    fn default() -> Self {
+        Self {
+            addr: String::new(),
+            path_prefix: String::new(),
+            rewrite: true,
+            connect_fail_use_original_host: false,
+            rewrite_host: false,
+            request_headers: HeaderRules::default(),
+            response_headers: HeaderRules::default(),
+            on_connect_fail: ConnectFailure::default(),
+            upstreams: Vec::new(),
+            balance: Balance::default(),
+            hash_header: None,
+            health_check: None,
+            mirror: None,
+            response: ResponseRewrite::default(),
+            mock: None,
+            record: None,
+            faults: Faults::default(),
+            mitm: None,
+        }
+    }
+}
+
+/// 转发地址连接失败时的处理
+///
+/// 依次尝试转发地址及备用地址，每个地址失败后按退避时间重试，全部失败时使用原始地址
+/// （connect_fail_use_original_host）或返回指定的状态码及内容
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConnectFailure {
+    /// 每个地址的重试次数
+    pub retries: u32,
+    /// 第一次重试前等待的毫秒数，之后每次加倍
+    pub backoff_ms: u64,
+    /// 转发地址连接失败后依次尝试的备用地址
+    pub alternates: Vec<String>,
+    /// 返回的状态码
+    pub status: u16,
+    /// 返回的内容
+    pub body: String,
+}
+
+impl Default for ConnectFailure {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            backoff_ms: 100,
+            alternates: Vec::new(),
+            status: 503,
+            body: "Service Unavailable".to_string(),
+        }
+    }
+}
+
+impl ConnectFailure {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 负载均衡策略
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// 轮询
+    #[default]
+    RoundRobin,
+    /// 随机
+    Random,
+    /// 最少连接
+    LeastConnections,
+    /// 按请求头或客户端IP一致性哈希
+    ConsistentHash,
+}
+
+impl Balance {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 主动健康检查，设置 path 时发送 HTTP 请求并要求 2xx/3xx 响应，否则只检查 TCP 连接
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HealthCheck {
+    /// 检查间隔毫秒数
+    pub interval_ms: u64,
+    /// 超时毫秒数
+    pub timeout_ms: u64,
+    /// HTTP 检查路径
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            interval_ms: 5000,
+            timeout_ms: 2000,
+            path: None,
+        }
+    }
+}
+
+/// 流量镜像，镜像的响应被丢弃，不影响原请求
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Mirror {
+    /// 镜像地址 host:port
+    pub addr: String,
+    /// 采样百分比，0-100
+    #[serde(default = "default_percent")]
+    pub percent: f64,
+    /// 记录镜像的响应状态及耗时，否则只在调试日志中输出
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub log_response: bool,
+}
+
+fn default_percent() -> f64 {
+    100.0
+}
+
+/// 响应改写
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ResponseRewrite {
+    /// 将 Location 及 Set-Cookie 的 Path、Domain 按路径前缀替换反向映射回客户端请求的地址
+    #[serde(skip_serializing_if = "is_false")]
+    pub reverse_rewrite: bool,
+    /// 替换状态码，如 `{ "404" = 200 }`，`*` 匹配所有状态码
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub status: BTreeMap<String, u16>,
+    /// 文本响应内容替换，按顺序执行
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub body: Vec<Substitution>,
+}
+
+impl ResponseRewrite {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 内容替换，find 和 regex 只能设置一个，regex 的替换内容中可用 `$1` 引用捕获组
+///
+/// `{ find = "http://127.0.0.1:8080", replace = "https://example.com" }`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Substitution {
+    /// 查找的字符串
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub find: Option<String>,
+    /// 查找的正则
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    /// 替换内容
+    pub replace: String,
+}
+
+/// 模拟响应，body 和 file 只能设置一个
+///
+/// 响应头及内容中可以使用变量 `{method}`、`{host}`、`{path}`（不含查询参数）、`{client_ip}`、
+/// `{query.名称}`、`{header.名称}`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Mock {
+    /// 状态码
+    pub status: u16,
+    /// 响应头
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// 响应内容
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// 响应内容文件，每次请求时读取
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// 返回响应前等待的毫秒数
+    #[serde(skip_serializing_if = "is_zero_u64")]
+    pub delay_ms: u64,
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Self {
+            status: 200,
+            headers: BTreeMap::new(),
+            body: None,
+            file: None,
+            delay_ms: 0,
+        }
+    }
+}
+
+/// 录制及回放，录制文件为目录下以规则名称命名的 `.jsonl` 或 `.har` 文件
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Record {
+    /// record 录制，replay 回放
+    pub mode: RecordMode,
+    /// 录制文件所在目录
+    pub dir: String,
+    /// 录制文件格式
+    #[serde(default, skip_serializing_if = "RecordFormat::is_default")]
+    pub format: RecordFormat,
+    /// 回放时同时按请求消息体的哈希匹配
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub match_body: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordMode {
+    /// 转发并录制
+    Record,
+    /// 只回放录制的响应，不连接转发地址
+    Replay,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordFormat {
+    /// 每行一个 HAR entry
+    #[default]
+    Jsonl,
+    /// HAR 1.2
+    Har,
+}
+
+impl RecordFormat {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 故障注入，每种故障按各自的百分比触发
+///
+/// HTTP 请求每个请求单独计算，其他连接在建立时计算
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Faults {
+    /// 返回第一个字节前等待
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<DelayFault>,
+    /// 限制传输速度
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<ThrottleFault>,
+    /// 直接断开连接
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset: Option<ResetFault>,
+    /// 只返回部分内容后断开连接
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncate: Option<TruncateFault>,
+    /// 不转发，直接返回指定的状态码，只用于 HTTP 请求
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<StatusFault>,
+}
+
+impl Faults {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 设置 max_ms 时在 ms 到 max_ms 之间随机等待
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DelayFault {
+    #[serde(default = "default_percent")]
+    pub percent: f64,
+    pub ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_ms: Option<u64>,
+}
+
+/// 每个方向的速度限制
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ThrottleFault {
+    #[serde(default = "default_percent")]
+    pub percent: f64,
+    /// KB/s
+    pub kbps: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ResetFault {
+    #[serde(default = "default_percent")]
+    pub percent: f64,
+}
+
+/// HTTP 请求截断响应内容，其他连接截断返回给客户端的数据
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TruncateFault {
+    #[serde(default = "default_percent")]
+    pub percent: f64,
+    /// 截断前返回的字节数
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StatusFault {
+    #[serde(default = "default_percent")]
+    pub percent: f64,
+    pub status: u16,
+    #[serde(default)]
+    pub body: String,
+}
+
+/// TLS 拦截，用本地 CA 签发的证书与客户端握手
+///
+/// CA 证书及私钥在第一次拦截时生成，保存在配置文件所在目录，客户端需要信任该证书
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Mitm {
+    /// 使用 TLS 连接转发地址，否则使用明文 HTTP，连接原始地址时总是使用 TLS
+    pub upstream_tls: bool,
+    /// 验证上游证书
+    pub verify: bool,
+}
+
+impl Default for Mitm {
+    fn default() -> Self {
+        Self {
+            upstream_tls: true,
+            verify: true,
+        }
+    }
+}
+
+/// 消息头改写规则，按 remove、set、add 的顺序执行
+///
+/// 值中可以使用变量 `{client_ip}`、`{host}`（原始 Host）、`{forward_host}`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HeaderRules {
+    /// 设置消息头，已存在时替换
+    pub set: BTreeMap<String, String>,
+    /// 添加消息头，已存在时保留原有的值
+    pub add: BTreeMap<String, String>,
+    /// 删除消息头
+    pub remove: Vec<String>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.add.is_empty() && self.remove.is_empty()
+    }
+}
+
+/// 本地管理接口，通过 JSON API 管理规则
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Admin {
+    /// 是否启用
+    pub enabled: bool,
+    /// 监听地址，默认只监听本机
+    pub listen_addr: String,
+    /// 请求需要携带 `Authorization: Bearer <token>`，没有设置时首次启动生成并保存到配置文件
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl Default for Admin {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_addr: "127.0.0.1:1090".to_string(),
+            token: None,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn is_zero(value: &i32) -> bool {
+    *value == 0
+}
+
+fn is_zero_u64(value: &u64) -> bool {
+    *value == 0
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct User {
+    /// 用户名
+    pub username: String,
+    /// 密码
+    pub password: String,
+}
+
+#[test]
+fn test_save_rules() {
+    let path =
+        std::env::temp_dir().join(format!("proxy-forward-config-{}.toml", std::process::id()));
+    let path = path.to_str().unwrap();
+    let content = "# 本机代理\nlisten_addr = \"127.0.0.1:2080\" # 端口\n\n[[rules]]\nname = \"old\"\n\
+         matcher = { addr = \"a.test\" }\nforward = { addr = \"127.0.0.1:80\" }\n";
+    std::fs::write(path, content).unwrap();
+    let mut config = AppConfig::load(path).unwrap();
+    config.rules[0].name = "new".to_string();
+    config.rules.push(Rule::default());
+    config.listen_addr = "0.0.0.0:1080".to_string();
+    config.save(path).unwrap();
+    let saved = std::fs::read_to_string(path).unwrap();
+    // 其他设置及注释保持不变
+    assert!(saved.starts_with("# 本机代理\nlisten_addr = \"127.0.0.1:2080\" # 端口\n"));
+    let loaded = AppConfig::load(path).unwrap();
+    assert_eq!(loaded.listen_addr, "127.0.0.1:2080");
+    assert_eq!(loaded.rules, config.rules);
+    config.admin.token = Some("secret".to_string());
+    config.save_admin_token(path).unwrap();
+    let loaded = AppConfig::load(path).unwrap();
+    assert_eq!(loaded.admin.token.as_deref(), Some("secret"));
+    assert_eq!(loaded.rules, config.rules);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_rule_fields() {
+    let rule: Rule = toml::from_str(
+        "matcher = { addr = \"a.test\" }\nforward = { addr = \"\", mock = { status = 204 } }",
+    )
+    .unwrap();
+    assert_eq!(rule.forward.mock.unwrap().status, 204);
+    // 转发配置写在匹配配置中时报错，而不是被忽略
+    let misplaced =
+        "matcher = { addr = \"a.test\", mock = { status = 204 } }\nforward = { addr = \"\" }";
+    assert!(toml::from_str::<Rule>(misplaced).is_err());
 }
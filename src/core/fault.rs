@@ -0,0 +1,290 @@
+use crate::core::config::Faults;
+use anyhow::anyhow;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, Sleep};
+
+/// 本次请求或连接触发的故障
+#[derive(Debug, Default)]
+pub(crate) struct Injection {
+    pub(crate) delay: Option<Duration>,
+    /// 字节/秒
+    pub(crate) throttle: Option<u64>,
+    pub(crate) reset: bool,
+    pub(crate) truncate: Option<u64>,
+    pub(crate) status: Option<(u16, String)>,
+}
+
+/// 检查故障配置
+pub(crate) fn validate(faults: &Faults) -> anyhow::Result<()> {
+    let percents = [
+        faults.delay.as_ref().map(|f| f.percent),
+        faults.throttle.as_ref().map(|f| f.percent),
+        faults.reset.as_ref().map(|f| f.percent),
+        faults.truncate.as_ref().map(|f| f.percent),
+        faults.status.as_ref().map(|f| f.percent),
+    ];
+    if let Some(percent) = percents
+        .into_iter()
+        .flatten()
+        .find(|p| !(0.0..=100.0).contains(p))
+    {
+        return Err(anyhow!("Invalid fault percent: {}", percent));
+    }
+    if let Some(delay) = &faults.delay
+        && delay.max_ms.is_some_and(|max| max < delay.ms)
+    {
+        return Err(anyhow!("Fault delay max_ms is less than ms"));
+    }
+    if faults.throttle.as_ref().is_some_and(|f| f.kbps == 0) {
+        return Err(anyhow!("Fault throttle kbps must be greater than 0"));
+    }
+    if let Some(status) = &faults.status
+        && !(200..600).contains(&status.status)
+    {
+        return Err(anyhow!("Invalid fault status: {}", status.status));
+    }
+    Ok(())
+}
+
+/// 按各自的百分比决定触发哪些故障
+pub(crate) fn roll(faults: &Faults) -> Injection {
+    let hit = |percent: f64| rand::random_bool(percent.clamp(0.0, 100.0) / 100.0);
+    Injection {
+        delay: faults.delay.as_ref().filter(|f| hit(f.percent)).map(|f| {
+            let ms = match f.max_ms {
+                Some(max) if max > f.ms => rand::random_range(f.ms..=max),
+                _ => f.ms,
+            };
+            Duration::from_millis(ms)
+        }),
+        throttle: faults
+            .throttle
+            .as_ref()
+            .filter(|f| hit(f.percent))
+            .map(|f| f.kbps.saturating_mul(1024)),
+        reset: faults.reset.as_ref().is_some_and(|f| hit(f.percent)),
+        truncate: faults
+            .truncate
+            .as_ref()
+            .filter(|f| hit(f.percent))
+            .map(|f| f.bytes),
+        status: faults
+            .status
+            .as_ref()
+            .filter(|f| hit(f.percent))
+            .map(|f| (f.status, f.body.clone())),
+    }
+}
+
+/// 截断数据后写入返回的错误
+#[derive(Debug)]
+struct Truncated;
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Truncated by fault injection")
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+/// 是否为截断数据时返回的错误
+pub(crate) fn is_truncated(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<Truncated>())
+}
+
+/// 可以中止的客户端连接
+pub(crate) trait Reset {
+    /// 关闭时发送 RST 而不是 FIN
+    fn reset(&self) -> io::Result<()>;
+}
+
+impl Reset for TcpStream {
+    fn reset(&self) -> io::Result<()> {
+        socket2::SockRef::from(self).set_linger(Some(Duration::ZERO))
+    }
+}
+
+impl<S: Reset> Reset for tokio_rustls::server::TlsStream<S> {
+    fn reset(&self) -> io::Result<()> {
+        self.get_ref().0.reset()
+    }
+}
+
+/// 测试用的内存连接没有 RST
+#[cfg(test)]
+impl Reset for tokio::io::DuplexStream {
+    fn reset(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 按速度限制读写，写入指定字节数后返回错误
+pub(crate) struct FaultStream<S> {
+    inner: S,
+    read: Option<Limiter>,
+    write: Option<Limiter>,
+    /// 截断前还可以写入的字节数
+    remaining: Option<u64>,
+    read_buf: Vec<u8>,
+}
+
+impl<S> FaultStream<S> {
+    /// throttle 为字节/秒，truncate 为截断前可以写入的字节数
+    pub(crate) fn new(inner: S, throttle: Option<u64>, truncate: Option<u64>) -> Self {
+        Self {
+            inner,
+            read: throttle.map(Limiter::new),
+            write: throttle.map(Limiter::new),
+            remaining: truncate,
+            read_buf: Vec::new(),
+        }
+    }
+}
+
+/// 按字节/秒限速，每次最多传输 100ms 的数据
+struct Limiter {
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Limiter {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+            sleep: None,
+        }
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(sleep) = self.sleep.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+        }
+        Poll::Ready(())
+    }
+
+    fn max_chunk(&self) -> usize {
+        (self.rate / 10).max(1) as usize
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.bytes += n as u64;
+        let due = self.start + Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        if due > Instant::now() {
+            self.sleep = Some(Box::pin(tokio::time::sleep_until(due)));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FaultStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(limiter) = this.read.as_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        ready!(limiter.poll_ready(cx));
+        let len = buf.remaining().min(limiter.max_chunk());
+        this.read_buf.resize(len, 0);
+        let mut limited = ReadBuf::new(&mut this.read_buf);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.put_slice(&this.read_buf[..n]);
+        limiter.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FaultStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let mut len = buf.len();
+        if let Some(remaining) = this.remaining {
+            if remaining == 0 {
+                return Poll::Ready(Err(io::Error::other(Truncated)));
+            }
+            len = len.min(remaining as usize);
+        }
+        if let Some(limiter) = this.write.as_mut() {
+            ready!(limiter.poll_ready(cx));
+            len = len.min(limiter.max_chunk());
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        if let Some(limiter) = this.write.as_mut() {
+            limiter.consume(n);
+        }
+        if let Some(remaining) = this.remaining.as_mut() {
+            *remaining -= n as u64;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_fault_stream() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let start = Instant::now();
+    let mut stream = FaultStream::new(Vec::new(), Some(10000), Some(2500));
+    // 每次最多写入 1000 字节，第二次写入在 100ms 后
+    stream.write_all(&[0; 2000]).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    let e = stream.write_all(&[0; 1000]).await.unwrap_err();
+    assert!(is_truncated(&e));
+    assert_eq!(stream.inner.len(), 2500);
+
+    let start = Instant::now();
+    let mut stream = FaultStream::new(&[1u8; 2500][..], Some(10000), None);
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data.len(), 2500);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn test_roll() {
+    use crate::core::config::{ResetFault, StatusFault};
+    let faults = Faults {
+        reset: Some(ResetFault { percent: 0.0 }),
+        status: Some(StatusFault {
+            percent: 100.0,
+            status: 503,
+            body: String::new(),
+        }),
+        ..Default::default()
+    };
+    assert!(validate(&faults).is_ok());
+    let injection = roll(&faults);
+    assert!(!injection.reset && injection.status.is_some() && injection.delay.is_none());
+    let faults = Faults {
+        reset: Some(ResetFault { percent: 101.0 }),
+        ..Default::default()
+    };
+    assert!(validate(&faults).is_err());
+}
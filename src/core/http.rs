@@ -1,222 +1,1121 @@
-use crate::core::route::RouteRule;
-use anyhow::Result;
+use crate::core::capture::{Capture, Tee};
+use crate::core::config::{self, HeaderRules};
+use crate::core::fault::{self, FaultStream, Reset};
+use crate::core::http_proxy::origin_form;
+use crate::core::mirror::MirrorRequest;
+use crate::core::mitm::{self, Upstream, UpstreamTls};
+use crate::core::record::{MAX_RECORD_BODY, decode_chunked};
+use crate::core::response::MAX_REWRITE_BODY;
+use crate::core::route::{Forward, RouteEngine, RouteRequest, RouteRule, split_host_port};
+use crate::core::socks::connect_target;
+use anyhow::{Result, anyhow};
+use bytes::{Buf, BytesMut};
 use httparse::Status;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
-pub(crate) async fn forward_handle(
-    client: TcpStream,
-    server: TcpStream,
-    rule: &RouteRule,
-) -> Result<()> {
-    // 创建缓冲区用于协议检测
-    let mut peek_buf = [0u8; 256];
-    let peek_size = client.peek(&mut peek_buf).await?;
-    let is_http = is_http(peek_buf.as_ref(), peek_size);
-
-    // 拆分客户端和服务器流为读写两半
-    let (mut client_reader, mut client_writer) = tokio::io::split(client);
-    let (mut server_reader, mut server_writer) = tokio::io::split(server);
-
-    let forward = match TcpStream::connect(&rule.forward.host).await {
-        Ok(ts) => Some(ts),
-        Err(e) => {
-            if rule.forward.connect_fail_use_original_host {
-                error!("Connect to forward host failed, use original host: {}", e);
-                let client_to_target = io::copy(&mut client_reader, &mut server_writer);
-                let target_to_client = io::copy(&mut server_reader, &mut client_writer);
-                tokio::try_join!(client_to_target, target_to_client)?;
-                return Ok(());
-            }
+/// 消息头最大长度
+pub(crate) const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// 消息头最大数量
+pub(crate) const MAX_HEADERS: usize = 64;
 
-            if let Some(path) = parse_path(peek_buf.as_ref())
-                && path.starts_with(rule.match_.prefix.as_str())
-            {
-                //转发服务连接不上，终止需要转发的请求
-                error!("Connect to forward host failed, stop access: {}", e);
-
-                if let Err(e) = client_writer
-                    .write_all(service_unavailable().as_slice())
-                    .await
-                {
-                    error!("Forward write error: {}", e);
-                }
+/// 请求的原始目标
+pub(crate) enum Origin {
+    /// SOCKS/CONNECT 隧道已连接的目标服务器
+    Tunnel(String, TcpStream),
+    /// HTTP代理，目标来自每个请求的绝对URI
+    Proxy,
+    /// 解密的 TLS 连接，连接上游时重新使用 TLS
+    Intercept {
+        addr: String,
+        /// 已完成握手的原始目标，握手失败时为空
+        server: Option<Upstream>,
+        /// 连接原始目标的 TLS 设置
+        upstream: UpstreamTls,
+        mitm: config::Mitm,
+    },
+}
 
-                server_writer.shutdown().await.unwrap_or(());
-                client_writer.shutdown().await.unwrap_or(());
-                return Err(e.into());
-            }
+/// 消息体长度
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    /// 读取到连接关闭为止
+    UntilClose,
+}
 
-            // 不进行修改转发的路径继续访问
-            None
-        }
-    };
-    let (mut forward_reader, mut forward_writer) = match forward {
-        None => (None, None),
-        Some(f) => {
-            let (reader, writer) = io::split(f);
-            (Some(reader), Some(writer))
+/// 请求头中与转发相关的信息
+#[derive(Debug)]
+struct RequestInfo {
+    method: String,
+    path: String,
+    host: Option<String>,
+    /// 请求头，值不是UTF-8的请求头被忽略
+    headers: Vec<(String, String)>,
+    body: BodyLength,
+    keep_alive: bool,
+    /// 客户端等待 100 Continue 后才发送消息体
+    expect_continue: bool,
+}
+
+/// 响应头中与转发相关的信息
+#[derive(Debug)]
+struct ResponseInfo {
+    status: u16,
+    body: BodyLength,
+    keep_alive: bool,
+}
+
+/// 带读缓冲的连接
+struct Conn<S> {
+    stream: S,
+    buf: BytesMut,
+    /// 已经处理过请求，上游可能已关闭空闲的连接
+    reused: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Conn<S> {
+    fn new(stream: S, early_data: &[u8]) -> Self {
+        let mut buf = BytesMut::with_capacity(8192);
+        buf.extend_from_slice(early_data);
+        Self {
+            stream,
+            buf,
+            reused: false,
         }
-    };
+    }
 
-    let client_writer = Arc::new(Mutex::new(client_writer));
-    let rule = rule.clone();
+    async fn fill(&mut self) -> io::Result<usize> {
+        self.buf.reserve(8192);
+        self.stream.read_buf(&mut self.buf).await
+    }
 
-    let client_writer_c = client_writer.clone();
-    let client_to_server = tokio::spawn(async move {
-        let mut buf = [0u8; 8192];
-        let mut flag = 0;
+    /// 读取完整的消息头，连接在消息开始前关闭时返回 None
+    async fn read_head(&mut self) -> Result<Option<BytesMut>> {
         loop {
-            let n = match client_reader.read(&mut buf).await {
-                Ok(n) if n == 0 => break, // EOF
-                Ok(n) => n,
-                Err(e) => {
-                    error!("Client read error: {}", e);
+            // 忽略消息之间多余的空行
+            while self.buf.starts_with(b"\r\n") {
+                self.buf.advance(2);
+            }
+            if let Some(pos) = memchr::memmem::find(&self.buf, b"\r\n\r\n") {
+                return Ok(Some(self.buf.split_to(pos + 4)));
+            }
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(anyhow!("Message head too large"));
+            }
+            if self.fill().await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(anyhow!("Connection closed in message head"));
+            }
+        }
+    }
+
+    /// 读取一行，包含结尾的CRLF
+    async fn read_line(&mut self) -> Result<BytesMut> {
+        loop {
+            if let Some(pos) = memchr::memmem::find(&self.buf, b"\r\n") {
+                return Ok(self.buf.split_to(pos + 2));
+            }
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(anyhow!("Line too long"));
+            }
+            if self.fill().await? == 0 {
+                return Err(anyhow!("Connection closed in message body"));
+            }
+        }
+    }
+
+    /// 转发 n 字节到 dst
+    async fn copy_exact<W: AsyncWrite + Unpin>(&mut self, mut n: u64, dst: &mut W) -> Result<()> {
+        while n > 0 {
+            if self.buf.is_empty() && self.fill().await? == 0 {
+                return Err(anyhow!("Connection closed in message body"));
+            }
+            let len = (self.buf.len() as u64).min(n) as usize;
+            dst.write_all(&self.buf[..len]).await?;
+            self.buf.advance(len);
+            n -= len as u64;
+        }
+        Ok(())
+    }
+
+    /// 读取并解码消息体，超过 limit 时返回错误
+    async fn read_body(&mut self, body: BodyLength, limit: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match body {
+            BodyLength::Empty => {}
+            BodyLength::Fixed(n) if n > limit as u64 => {
+                return Err(anyhow!("Body larger than {} bytes", limit));
+            }
+            BodyLength::Fixed(n) => self.copy_exact(n, &mut data).await?,
+            BodyLength::Chunked => loop {
+                let size = parse_chunk_size(&self.read_line().await?)?;
+                if size == 0 {
+                    // 丢弃 trailer
+                    while self.read_line().await?.as_ref() != b"\r\n" {}
                     break;
                 }
+                if data.len() as u64 + size > limit as u64 {
+                    return Err(anyhow!("Body larger than {} bytes", limit));
+                }
+                self.copy_exact(size, &mut data).await?;
+                self.read_line().await?;
+            },
+            BodyLength::UntilClose => {
+                data.extend_from_slice(&self.buf);
+                self.buf.clear();
+                let remaining = (limit + 1).saturating_sub(data.len()) as u64;
+                (&mut self.stream)
+                    .take(remaining)
+                    .read_to_end(&mut data)
+                    .await?;
+                if data.len() > limit {
+                    return Err(anyhow!("Body larger than {} bytes", limit));
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// 将完整的消息体读入缓冲区但不消费，超过 limit 字节时返回 false
+    async fn buffer_body(&mut self, body: BodyLength, limit: usize) -> Result<bool> {
+        loop {
+            let len = match body {
+                BodyLength::Empty => Some(0),
+                BodyLength::Fixed(n) if n > limit as u64 => return Ok(false),
+                BodyLength::Fixed(n) => (self.buf.len() as u64 >= n).then_some(n),
+                BodyLength::Chunked => chunked_len(&self.buf)?.map(|n| n as u64),
+                BodyLength::UntilClose => None,
             };
-            debug!("flag: {}", flag);
-            flag += 1;
+            if let Some(len) = len {
+                return Ok(len <= limit as u64);
+            }
+            if self.buf.len() > limit {
+                return Ok(false);
+            }
+            if self.fill().await? == 0 {
+                if body == BodyLength::UntilClose {
+                    return Ok(true);
+                }
+                return Err(anyhow!("Connection closed in message body"));
+            }
+        }
+    }
 
-            // 如果是HTTP流量，可以进行修改
-            if !is_http {
-                if let Err(e) = server_writer.write_all(&buf[..n]).await {
-                    error!("Server write error: {}", e);
+    /// 按消息体长度原样转发消息体
+    async fn copy_body<W: AsyncWrite + Unpin>(
+        &mut self,
+        body: BodyLength,
+        dst: &mut W,
+    ) -> Result<()> {
+        match body {
+            BodyLength::Empty => {}
+            BodyLength::Fixed(n) => self.copy_exact(n, dst).await?,
+            BodyLength::Chunked => loop {
+                let line = self.read_line().await?;
+                dst.write_all(&line).await?;
+                let size = parse_chunk_size(&line)?;
+                if size == 0 {
+                    // trailer 以空行结束
+                    loop {
+                        let line = self.read_line().await?;
+                        dst.write_all(&line).await?;
+                        if line.as_ref() == b"\r\n" {
+                            break;
+                        }
+                    }
                     break;
                 }
-                continue;
+                // 块数据及结尾的CRLF
+                self.copy_exact(size + 2, dst).await?;
+            },
+            BodyLength::UntilClose => {
+                dst.write_all(&self.buf).await?;
+                self.buf.clear();
+                tokio::io::copy(&mut self.stream, dst).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 逐个处理HTTP/1.1请求，每个请求单独匹配路由规则
+///
+/// 请求串行处理，上一个响应完整返回后才读取下一个请求，保证响应顺序与请求顺序一致
+pub(crate) async fn forward_handle<S>(
+    client: S,
+    client_addr: SocketAddr,
+    early_data: &[u8],
+    origin: Origin,
+    route_engine: Arc<RouteEngine>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Reset + Unpin,
+{
+    let mut client = Conn::new(client, early_data);
+    // 上游连接，按地址复用
+    let mut upstreams: HashMap<String, Conn<Upstream>> = HashMap::new();
+    // 拦截 TLS 时原始目标及转发地址的 TLS 设置
+    let (tunnel_addr, intercept) = match origin {
+        Origin::Tunnel(addr, server) => {
+            upstreams.insert(addr.clone(), Conn::new(Upstream::Plain(server), &[]));
+            (Some(addr), None)
+        }
+        Origin::Proxy => (None, None),
+        Origin::Intercept {
+            addr,
+            server,
+            upstream,
+            mitm,
+        } => {
+            if let Some(server) = server {
+                upstreams.insert(addr.clone(), Conn::new(server, &[]));
             }
-            match modify_http_data(&mut buf[..n], &rule) {
+            (Some(addr), Some((upstream, mitm)))
+        }
+    };
+    let original_tls = intercept.as_ref().map(|(upstream, _)| upstream);
+
+    while let Some(head) = client.read_head().await? {
+        let Some(request) = parse_request(&head) else {
+            client
+                .stream
+                .write_all(&simple_response("400 Bad Request", ""))
+                .await?;
+            return Err(anyhow!("Invalid HTTP request"));
+        };
+        // 原始目标地址，代理模式下将请求转换为 origin-form
+        let (original, original_head, path, authority) = match &tunnel_addr {
+            Some(addr) => (addr.clone(), head.to_vec(), request.path.clone(), None),
+            None => match origin_form(&head) {
+                Some((addr, head, path, authority)) => (addr, head, path, Some(authority)),
                 None => {
-                    if let Err(e) = server_writer.write_all(&buf[..n]).await {
-                        error!("Server write error: {}", e);
-                        break;
-                    }
+                    client
+                        .stream
+                        .write_all(&simple_response("400 Bad Request", ""))
+                        .await?;
+                    return Err(anyhow!("Not an absolute URI: {}", request.path));
+                }
+            },
+        };
+        let host = request
+            .host
+            .clone()
+            .or(authority)
+            .unwrap_or(original.clone());
+
+        // 匹配路由规则时转发到规则指定的地址
+        let route_request = RouteRequest {
+            method: &request.method,
+            host: &host,
+            path: &path,
+            headers: &request.headers,
+        };
+        let rule = route_engine.resolve_target(&route_request).await;
+        if rule.is_none() && tracing::enabled!(tracing::Level::DEBUG) {
+            debug!("{}", route_engine.explain(&route_request).await);
+        }
+        let client_ip = client_addr.ip().to_canonical();
+        // 模拟响应或回放录制的响应，不连接上游
+        let replay = rule
+            .as_ref()
+            .and_then(|r| r.forward.record.as_ref())
+            .filter(|r| r.replaying());
+        if let Some(rule) = &rule
+            && (rule.forward.mock.is_some() || replay.is_some())
+        {
+            if request.expect_continue && request.body != BodyLength::Empty {
+                client
+                    .stream
+                    .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                    .await?;
+            }
+            let response = match (&rule.forward.mock, replay) {
+                (Some(mock), _) => {
+                    client
+                        .copy_body(request.body, &mut tokio::io::sink())
+                        .await?;
+                    debug!(
+                        "[{}] {} {}{} -> mock {}",
+                        rule.name, request.method, host, path, mock.status
+                    );
+                    tokio::time::sleep(mock.delay).await;
+                    mock.render(&route_request, client_ip, request.keep_alive)
+                        .await
+                        .map_err(|e| anyhow!("Mock response failed: {}", e))
                 }
-                Some(d) => {
-                    if let Some(fw) = &mut forward_writer {
-                        debug!("forward_writer: {}", &rule.forward.host);
-                        if let Err(e) = fw.write_all(&*d).await {
-                            error!("Forward write error: {}", e);
+                (None, Some(recorder)) => {
+                    let body = match client.read_body(request.body, MAX_RECORD_BODY).await {
+                        Ok(body) => body,
+                        Err(e) => {
+                            // 未读取的消息体之后无法确定下一个请求的位置
+                            error!("[{}] Read request body for replay failed: {}", rule.name, e);
+                            let response = error_response(413, &e.to_string());
+                            client.stream.write_all(&response).await?;
                             break;
                         }
-                    } else if let Err(e) = client_writer_c
-                        .lock()
-                        .await
-                        .write_all(service_unavailable().as_slice())
+                    };
+                    debug!(
+                        "[{}] {} {}{} -> replay",
+                        rule.name, request.method, host, path
+                    );
+                    recorder
+                        .replay(&route_request, &body, request.keep_alive)
                         .await
-                    {
-                        error!("Forward write error: {}", e);
-                        break;
-                    }
                 }
+                (None, None) => unreachable!(),
+            };
+            match response {
+                Ok(response) => client.stream.write_all(&response).await?,
+                Err(e) => {
+                    error!("[{}] {}", rule.name, e);
+                    let body = e.to_string();
+                    client.stream.write_all(&error_response(500, &body)).await?;
+                    break;
+                }
+            }
+            if !request.keep_alive {
+                break;
             }
+            continue;
         }
-        server_writer.shutdown().await.unwrap_or(());
-        if let Some(fw) = &mut forward_writer {
-            fw.shutdown().await.unwrap_or(());
+        // 按规则的比例注入故障
+        let injection = rule
+            .as_ref()
+            .map(|r| fault::roll(&r.forward.faults))
+            .unwrap_or_default();
+        if let Some(rule) = &rule {
+            if injection.reset {
+                debug!(
+                    "[{}] {} {}{} -> fault reset",
+                    rule.name, request.method, host, path
+                );
+                client.stream.reset()?;
+                return Ok(());
+            }
+            if let Some((status, body)) = &injection.status {
+                debug!(
+                    "[{}] {} {}{} -> fault {}",
+                    rule.name, request.method, host, path, status
+                );
+                client
+                    .stream
+                    .write_all(&error_response(*status, body))
+                    .await?;
+                break;
+            }
         }
-    });
-
-    let server_to_client = tokio::spawn(async move {
-        let client_writer0 = client_writer.clone();
-        let handle0 = tokio::spawn(async move {
-            let mut buf = [0u8; 8192];
-            loop {
-                let n = match server_reader.read(&mut buf).await {
-                    Ok(n) if n == 0 => break, // EOF
-                    Ok(n) => n,
+        // 转发到规则的上游地址时改写请求及响应
+        let (addr, mut head, forwarded, tls) = match &rule {
+            Some(rule) => {
+                let hash_key = rule.forward.pool.hash_key(&request.headers, client_ip);
+                // 拦截 TLS 时按规则的设置连接转发地址，规则没有设置时与原始目标相同
+                let mitm = intercept
+                    .as_ref()
+                    .map(|(_, mitm)| rule.forward.mitm.as_ref().unwrap_or(mitm))
+                    .filter(|m| m.upstream_tls);
+                match connect_forward(&mut upstreams, rule, &hash_key, mitm).await {
+                    Ok(addr) => {
+                        let head = rewrite_target(&original_head, rule).unwrap_or(original_head);
+                        let tls = mitm.map(|m| forward_tls(m, &addr));
+                        (addr, head, Some(rule), tls)
+                    }
+                    Err(e) if rule.forward.connect_fail_use_original_host => {
+                        error!(
+                            "[{}] Connect to forward host failed, use original host: {}",
+                            rule.name, e
+                        );
+                        if let Err(e) =
+                            ensure_upstream(&mut upstreams, &original, original_tls).await
+                        {
+                            client.stream.write_all(&gateway_error(&e)).await?;
+                            return Err(e.into());
+                        }
+                        (original.clone(), original_head, None, original_tls.cloned())
+                    }
                     Err(e) => {
-                        error!("Server read error: {}", e);
-                        break;
+                        //转发服务连接不上，终止需要转发的请求
+                        error!(
+                            "[{}] Connect to forward host failed, stop access: {}",
+                            rule.name, e
+                        );
+                        let failure = &rule.forward.on_connect_fail;
+                        client
+                            .stream
+                            .write_all(&error_response(failure.status, &failure.body))
+                            .await?;
+                        return Err(e.into());
                     }
-                };
-
-                if let Err(e) = client_writer0.lock().await.write_all(&buf[..n]).await {
-                    error!("Client write error: {}", e);
-                    break;
                 }
             }
-        });
+            None => {
+                if let Err(e) = ensure_upstream(&mut upstreams, &original, original_tls).await {
+                    client.stream.write_all(&gateway_error(&e)).await?;
+                    return Err(e.into());
+                }
+                (original.clone(), original_head, None, original_tls.cloned())
+            }
+        };
+        let forward = forwarded.map(|r| &r.forward);
+        let context = HeaderContext {
+            client_ip,
+            host: &host,
+            forward_host: &addr,
+        };
+        if let Some(forward) = forward {
+            head = rewrite_headers(&head, &request_rules(forward, &addr), &context);
+        }
+        // 日志中标记处理请求的规则
+        let rule_name = rule.as_ref().map_or("-", |r| r.name.as_str());
+        debug!(
+            "[{}] {} {}{} -> {}",
+            rule_name, request.method, host, path, addr
+        );
+        // 最少连接策略统计正在处理的请求
+        let _active = forward.and_then(|f| f.pool.acquire(&addr));
+        // 录制请求及响应
+        let recorder = forward
+            .and_then(|f| f.record.as_ref())
+            .filter(|r| r.recording());
+        let (started, timer) = (SystemTime::now(), Instant::now());
+        let mut request_capture = recorder.map(|_| Capture::new(MAX_RECORD_BODY));
+        let mut response_capture = recorder.map(|_| Capture::new(MAX_RECORD_BODY));
 
-        let client_writer1 = client_writer.clone();
-        let handle1 = tokio::spawn(async move {
-            if let Some(fr) = &mut forward_reader {
-                let mut buf = [0u8; 8192];
-                loop {
-                    let n = match fr.read(&mut buf).await {
-                        Ok(n) if n == 0 => break, // EOF
-                        Ok(n) => n,
+        // 处理请求期间从连接表中取出，可以复用时再放回
+        let mut upstream = upstreams
+            .remove(&addr)
+            .ok_or(anyhow!("Upstream {} not connected", addr))?;
+        // 复用的连接可能已被上游关闭，没有收到响应时重新连接并重发没有消息体的幂等请求
+        let mut first_head = None;
+        if upstream.reused && request.body == BodyLength::Empty && is_idempotent(&request.method) {
+            let sent = match upstream.stream.write_all(&head).await {
+                Ok(()) => upstream.read_head().await,
+                Err(e) => Err(e.into()),
+            };
+            match sent {
+                Ok(Some(response_head)) => first_head = Some(response_head),
+                Err(e) if e.downcast_ref::<io::Error>().is_none() => return Err(e),
+                Ok(None) | Err(_) => {
+                    debug!(
+                        "[{}] Reused upstream {} closed before response, reconnect",
+                        rule_name, addr
+                    );
+                    upstream = match connect_upstream(&addr, tls.as_ref()).await {
+                        Ok(upstream) => upstream,
                         Err(e) => {
-                            error!("Forward read error: {}", e);
-                            break;
+                            client.stream.write_all(&gateway_error(&e)).await?;
+                            return Err(e.into());
                         }
                     };
-                    if let Err(e) = client_writer1.lock().await.write_all(&buf[..n]).await {
-                        error!("Client write error: {}", e);
-                        break;
-                    }
+                    upstream.stream.write_all(&head).await?;
                 }
             }
-        });
+        } else {
+            upstream.stream.write_all(&head).await?;
+        }
+        // 按采样比例镜像请求，协议升级的请求不镜像
+        let mut mirror = forward
+            .and_then(|f| f.mirror.as_ref())
+            .filter(|_| !contains_name(request.headers.iter().map(|(n, _)| n), b"upgrade"))
+            .and_then(|m| {
+                let target = format!("{} {}{}", request.method, host, path);
+                MirrorRequest::sample(m, rule_name, target, mirror_head(&head, &context))
+            });
+        // Expect: 100-continue 时等上游返回 100 后再转发消息体
+        let mut body_pending = request.body != BodyLength::Empty;
+        if body_pending && !request.expect_continue {
+            let captures = [
+                mirror.as_mut().map(|m| &mut m.body),
+                request_capture.as_mut(),
+            ];
+            send_body(
+                &mut client,
+                &mut upstream,
+                request.body,
+                captures,
+                injection.throttle,
+            )
+            .await?;
+            body_pending = false;
+        }
+        if !body_pending && let Some(mirror) = mirror.take() {
+            mirror.spawn();
+        }
 
-        let _ = tokio::try_join!(handle0, handle1);
-        client_writer.lock().await.shutdown().await.unwrap_or(());
-    });
+        // 延迟返回响应的第一个字节
+        if let Some(delay) = injection.delay {
+            tokio::time::sleep(delay).await;
+        }
+        // 读取响应，1xx 中间响应直接转发给客户端
+        let (response, response_head) = loop {
+            // 等待 100 期间客户端可能不再等待直接发送消息体（如 curl 等待 1 秒后），此时直接转发，
+            // 避免与忽略 Expect 的上游互相等待，None 表示客户端的消息体已可读
+            let next = if let Some(response_head) = first_head.take() {
+                Some(Some(response_head))
+            } else if !body_pending {
+                Some(upstream.read_head().await?)
+            } else if !client.buf.is_empty() {
+                None
+            } else {
+                tokio::select! {
+                    head = upstream.read_head() => Some(head?),
+                    n = client.fill() => {
+                        if n? == 0 {
+                            return Err(anyhow!("[{}] Client closed before request body", rule_name));
+                        }
+                        None
+                    }
+                }
+            };
+            let Some(response_head) = next else {
+                let captures = [
+                    mirror.as_mut().map(|m| &mut m.body),
+                    request_capture.as_mut(),
+                ];
+                send_body(
+                    &mut client,
+                    &mut upstream,
+                    request.body,
+                    captures,
+                    injection.throttle,
+                )
+                .await?;
+                body_pending = false;
+                if let Some(mirror) = mirror.take() {
+                    mirror.spawn();
+                }
+                continue;
+            };
+            let Some(response_head) = response_head else {
+                client
+                    .stream
+                    .write_all(&simple_response("502 Bad Gateway", ""))
+                    .await?;
+                return Err(anyhow!(
+                    "[{}] Upstream {} closed before response",
+                    rule_name,
+                    addr
+                ));
+            };
+            let Some(response) = parse_response(&response_head, &request.method) else {
+                client
+                    .stream
+                    .write_all(&simple_response("502 Bad Gateway", ""))
+                    .await?;
+                return Err(anyhow!(
+                    "[{}] Invalid HTTP response from {}",
+                    rule_name,
+                    addr
+                ));
+            };
+            if response.status >= 200 && response.status != 101 {
+                break (response, response_head);
+            }
+            client.stream.write_all(&response_head).await?;
+            if response.status == 101 {
+                // 协议升级，之后双向转发原始数据
+                return upgrade(client, upstream).await;
+            }
+            if response.status == 100 && body_pending {
+                let captures = [
+                    mirror.as_mut().map(|m| &mut m.body),
+                    request_capture.as_mut(),
+                ];
+                send_body(
+                    &mut client,
+                    &mut upstream,
+                    request.body,
+                    captures,
+                    injection.throttle,
+                )
+                .await?;
+                body_pending = false;
+                if let Some(mirror) = mirror.take() {
+                    mirror.spawn();
+                }
+            }
+        };
+        // 转发到规则的上游地址时改写响应
+        let mut response_head = response_head.to_vec();
+        let mut response_body = None;
+        if let Some(rule) = forwarded {
+            let forward = &rule.forward;
+            response_head = rewrite_headers(&response_head, &forward.response_headers, &context);
+            let rewrite = &forward.response;
+            if !rewrite.is_empty() {
+                response_head = rewrite.rewrite_head(&response_head, rule, &host, &addr);
+            }
+            // 消息体过大时原样转发已缓冲的部分及剩余部分
+            if response.body != BodyLength::Empty
+                && rewrite.rewrites_body(&response_head)
+                && upstream
+                    .buffer_body(response.body, MAX_REWRITE_BODY)
+                    .await?
+            {
+                let body = match upstream.read_body(response.body, MAX_REWRITE_BODY).await {
+                    Ok(body) => body,
+                    Err(e) => {
+                        client
+                            .stream
+                            .write_all(&simple_response("502 Bad Gateway", ""))
+                            .await?;
+                        return Err(anyhow!("[{}] Rewrite response failed: {}", rule_name, e));
+                    }
+                };
+                let body = rewrite.rewrite_body(&response_head, body);
+                response_head =
+                    rewrite_headers(&response_head, &length_rules(body.len()), &context);
+                response_body = Some(body);
+            } else if response.body == BodyLength::Empty
+                && matches!(response.status, 204 | 304)
+                && rewrite.status_for(response.status).is_some()
+                && !request.method.eq_ignore_ascii_case("HEAD")
+            {
+                // 原响应没有消息体，替换的状态码需要明确长度
+                response_head = rewrite_headers(&response_head, &length_rules(0), &context);
+            }
+        }
+        client.stream.write_all(&response_head).await?;
+        // 改写后的消息体已解码
+        let response_chunked = response_body.is_none() && response.body == BodyLength::Chunked;
+        let (throttle, truncate) = (injection.throttle, injection.truncate);
+        let written = match response_body {
+            Some(body) => {
+                if let Some(capture) = response_capture.as_mut() {
+                    capture.append(&body);
+                }
+                let mut dst = FaultStream::new(&mut client.stream, throttle, truncate);
+                dst.write_all(&body).await.map_err(Into::into)
+            }
+            None => {
+                let dst = Tee::new(&mut client.stream, response_capture.as_mut());
+                let mut dst = FaultStream::new(dst, throttle, truncate);
+                upstream.copy_body(response.body, &mut dst).await
+            }
+        };
+        if let Err(e) = written {
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(fault::is_truncated)
+            {
+                // 截断响应后关闭连接
+                debug!("[{}] Response truncated by fault injection", rule_name);
+                break;
+            }
+            return Err(e);
+        }
+        if let (Some(recorder), Some(request_capture), Some(response_capture)) =
+            (recorder, &request_capture, &response_capture)
+        {
+            let chunked = request.body == BodyLength::Chunked;
+            let request_body = recorded_body(request_capture, chunked);
+            let response_body = recorded_body(response_capture, response_chunked);
+            recorder.save(
+                &route_request,
+                request_body,
+                &response_head,
+                response_body,
+                started,
+                timer.elapsed(),
+            );
+        }
 
-    // 等待两个方向的任务完成
-    let _ = tokio::try_join!(client_to_server, server_to_client);
+        // 上游未等消息体就返回了最终响应，客户端可能仍会发送消息体，无法确定下一个请求的位置
+        if response.keep_alive && !body_pending {
+            upstream.reused = true;
+            upstreams.insert(addr.clone(), upstream);
+        }
+        if !request.keep_alive || !response.keep_alive || body_pending {
+            break;
+        }
+    }
+    client.stream.shutdown().await.unwrap_or(());
     debug!("Request handle finished");
     Ok(())
 }
 
-fn modify_http_data(data: &mut [u8], rule: &RouteRule) -> Option<Vec<u8>> {
-    if data.len() >= 4 && data.starts_with(b"HTTP/") {
-        //响应数据
-        return None;
+/// 转发请求消息体，同时复制到镜像及录制的缓冲区
+async fn send_body<S>(
+    client: &mut Conn<S>,
+    upstream: &mut Conn<Upstream>,
+    body: BodyLength,
+    captures: [Option<&mut Capture>; 2],
+    throttle: Option<u64>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let dst = Tee::new(&mut upstream.stream, captures.into_iter().flatten());
+    let mut dst = FaultStream::new(dst, throttle, None);
+    client.copy_body(body, &mut dst).await
+}
+
+/// 按负载均衡策略依次连接上游地址，再按规则的失败策略连接备用地址，返回连接成功的地址
+async fn connect_forward(
+    upstreams: &mut HashMap<String, Conn<Upstream>>,
+    rule: &RouteRule,
+    hash_key: &str,
+    mitm: Option<&config::Mitm>,
+) -> io::Result<String> {
+    let failure = &rule.forward.on_connect_fail;
+    let candidates = rule.forward.pool.candidates(hash_key);
+    let alternates = failure.alternates.iter().map(String::as_str);
+    let mut last_error = None;
+    for addr in candidates.into_iter().chain(alternates) {
+        for attempt in 0..=failure.retries {
+            if attempt > 0 {
+                // 指数退避
+                let backoff = failure
+                    .backoff_ms
+                    .saturating_mul(1 << (attempt - 1).min(16));
+                tokio::time::sleep(Duration::from_millis(backoff)).await;
+            }
+            let tls = mitm.map(|m| forward_tls(m, addr));
+            match ensure_upstream(upstreams, addr, tls.as_ref()).await {
+                Ok(()) => return Ok(addr.to_string()),
+                Err(e) => {
+                    warn!(
+                        "[{}] Connect to {} failed (attempt {}/{}): {}",
+                        rule.name,
+                        addr,
+                        attempt + 1,
+                        failure.retries + 1,
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+    Err(last_error.unwrap_or(io::ErrorKind::NotConnected.into()))
+}
+
+/// 确保已连接到上游地址
+async fn ensure_upstream(
+    upstreams: &mut HashMap<String, Conn<Upstream>>,
+    addr: &str,
+    tls: Option<&UpstreamTls>,
+) -> io::Result<()> {
+    if !upstreams.contains_key(addr) {
+        let upstream = connect_upstream(addr, tls).await?;
+        upstreams.insert(addr.to_string(), upstream);
+    }
+    Ok(())
+}
+
+/// 连接上游地址，拦截 TLS 时重新使用 TLS
+async fn connect_upstream(addr: &str, tls: Option<&UpstreamTls>) -> io::Result<Conn<Upstream>> {
+    let stream = connect_target(addr).await?;
+    let stream = match tls {
+        Some(tls) => Upstream::Tls(Box::new(mitm::connect(stream, tls).await?)),
+        None => Upstream::Plain(stream),
+    };
+    Ok(Conn::new(stream, &[]))
+}
+
+/// 转发地址的 TLS 设置，使用转发地址的主机名验证证书
+pub(crate) fn forward_tls(mitm: &config::Mitm, addr: &str) -> UpstreamTls {
+    UpstreamTls {
+        server_name: split_host_port(addr).0.to_string(),
+        verify: mitm.verify,
     }
+}
+
+/// 幂等的请求方法，上游没有响应时可以重发
+fn is_idempotent(method: &str) -> bool {
+    ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"]
+        .iter()
+        .any(|m| m.eq_ignore_ascii_case(method))
+}
+
+/// 协议升级后双向转发，先转发双方已缓冲的数据
+async fn upgrade<S>(mut client: Conn<S>, mut upstream: Conn<Upstream>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    upstream.stream.write_all(&client.buf).await?;
+    client.stream.write_all(&upstream.buf).await?;
+    tokio::io::copy_bidirectional(&mut client.stream, &mut upstream.stream).await?;
+    Ok(())
+}
 
-    let mut headers = [httparse::EMPTY_HEADER; 16];
+fn parse_request(head: &[u8]) -> Option<RequestInfo> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
+    if !matches!(req.parse(head), Ok(Status::Complete(_))) {
+        return None;
+    }
+    let body = match body_length(req.headers)? {
+        // 请求没有长度信息时没有消息体
+        None => BodyLength::Empty,
+        // 请求不能以关闭连接表示结束
+        Some(BodyLength::UntilClose) => return None,
+        Some(body) => body,
+    };
+    Some(RequestInfo {
+        method: req.method?.to_string(),
+        path: req.path?.to_string(),
+        host: header_value(req.headers, "host").map(str::to_string),
+        headers: req
+            .headers
+            .iter()
+            .filter_map(|h| {
+                Some((
+                    h.name.to_string(),
+                    std::str::from_utf8(h.value).ok()?.to_string(),
+                ))
+            })
+            .collect(),
+        body,
+        keep_alive: keep_alive(req.version?, req.headers),
+        expect_continue: req.version? >= 1
+            && header_value(req.headers, "expect")
+                .is_some_and(|v| v.trim().eq_ignore_ascii_case("100-continue")),
+    })
+}
 
-    match req.parse(data) {
-        Ok(Status::Complete(_pos)) => {
-            // 解析成功，修改URL
-            if let Some(path) = req.path {
-                // debug!("Original data: {}", String::from_utf8_lossy(data));
-                // debug!("Original data: {:?}", std::str::from_utf8(data));
-
-                // 创建新的URL路径
-                let prefix = &rule.match_.prefix;
-                if prefix.is_empty()
-                    || prefix.eq("/")
-                    || !path.starts_with(prefix)
-                    || !rule.forward.rewrite
-                {
-                    return None;
-                };
-                debug!("Original URL path: {}", path);
-                let new_path = path.replacen(prefix, &rule.forward.prefix, 1);
-                debug!("Modified URL path: {}", new_path);
-
-                let data_str = std::str::from_utf8(data)
-                    .unwrap()
-                    .to_string()
-                    // .replacen("\r\nConnection: keep-alive", "", 1)
-                    .replacen(path, new_path.as_str(), 1);
-                Some(data_str.into_bytes())
-            } else {
-                None
-            }
+/// 只读取响应的状态行，返回状态码
+pub(crate) async fn read_status<R: AsyncRead + Unpin>(stream: &mut R) -> Result<u16> {
+    let mut buf = [0u8; 64];
+    let mut len = 0;
+    while len < 12 {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            break;
         }
-        _ => {
-            // 非完整HTTP请求或不支持的格式
-            None
+        len += n;
+    }
+    std::str::from_utf8(&buf[..len])
+        .ok()
+        .and_then(|line| line.strip_prefix("HTTP/1."))
+        .and_then(|line| line.get(2..5))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or(anyhow!("Invalid HTTP response"))
+}
+
+fn parse_response(head: &[u8], method: &str) -> Option<ResponseInfo> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    if !matches!(res.parse(head), Ok(Status::Complete(_))) {
+        return None;
+    }
+    let status = res.code?;
+    let body =
+        if method.eq_ignore_ascii_case("HEAD") || status < 200 || status == 204 || status == 304 {
+            BodyLength::Empty
+        } else {
+            body_length(res.headers)?.unwrap_or(BodyLength::UntilClose)
+        };
+    Some(ResponseInfo {
+        status,
+        body,
+        keep_alive: keep_alive(res.version?, res.headers) && body != BodyLength::UntilClose,
+    })
+}
+
+/// 根据 Transfer-Encoding 和 Content-Length 确定消息体长度，没有长度信息时返回 Some(None)
+fn body_length(headers: &[httparse::Header]) -> Option<Option<BodyLength>> {
+    // Transfer-Encoding 优先于 Content-Length
+    if let Some(te) = header_value(headers, "transfer-encoding") {
+        let chunked = te
+            .rsplit(',')
+            .next()
+            .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"));
+        return Some(Some(if chunked {
+            BodyLength::Chunked
+        } else {
+            BodyLength::UntilClose
+        }));
+    }
+    match header_value(headers, "content-length") {
+        Some(len) => match len.trim().parse::<u64>().ok()? {
+            0 => Some(Some(BodyLength::Empty)),
+            n => Some(Some(BodyLength::Fixed(n))),
+        },
+        None => Some(None),
+    }
+}
+
+fn keep_alive(version: u8, headers: &[httparse::Header]) -> bool {
+    let connection = header_value(headers, "connection")
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let has = |token: &str| connection.split(',').any(|t| t.trim() == token);
+    if has("close") {
+        return false;
+    }
+    // HTTP/1.0 默认不保持连接
+    version >= 1 || has("keep-alive")
+}
+
+fn header_value<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<u64> {
+    let line = std::str::from_utf8(line).map_err(|_| anyhow!("Invalid chunk size"))?;
+    // 忽略 chunk-ext
+    let size = line.split(';').next().unwrap_or_default().trim();
+    u64::from_str_radix(size, 16).map_err(|_| anyhow!("Invalid chunk size: {}", size))
+}
+
+/// 缓冲区中完整的 chunked 消息体（包含 trailer）的长度，不完整时返回 None
+fn chunked_len(buf: &[u8]) -> Result<Option<usize>> {
+    let mut pos = 0;
+    loop {
+        let Some(end) = memchr::memmem::find(&buf[pos..], b"\r\n") else {
+            return Ok(None);
+        };
+        let size = parse_chunk_size(&buf[pos..pos + end])?;
+        pos += end + 2;
+        if size == 0 {
+            break;
+        }
+        pos = pos.saturating_add(usize::try_from(size)?).saturating_add(2);
+        if pos > buf.len() {
+            return Ok(None);
+        }
+    }
+    // trailer 以空行结束
+    loop {
+        let Some(end) = memchr::memmem::find(&buf[pos..], b"\r\n") else {
+            return Ok(None);
+        };
+        pos += end + 2;
+        if end == 0 {
+            return Ok(Some(pos));
+        }
+    }
+}
+
+/// 消息头改写时可用的变量
+struct HeaderContext<'a> {
+    client_ip: IpAddr,
+    /// 原始 Host
+    host: &'a str,
+    forward_host: &'a str,
+}
+
+impl HeaderContext<'_> {
+    fn expand(&self, value: &str) -> String {
+        value
+            .replace("{client_ip}", &self.client_ip.to_string())
+            .replace("{host}", self.host)
+            .replace("{forward_host}", self.forward_host)
+    }
+}
+
+/// 转发请求时的请求头改写规则，rewrite_host 时设置 Host 为连接的上游地址
+fn request_rules<'a>(forward: &'a Forward, addr: &str) -> Cow<'a, HeaderRules> {
+    if !forward.rewrite_host {
+        return Cow::Borrowed(&forward.request_headers);
+    }
+    let mut rules = forward.request_headers.clone();
+    rules.set.insert("Host".to_string(), addr.to_string());
+    Cow::Owned(rules)
+}
+
+/// 录制的消息体及长度，分块传输时解码，超过最大长度时没有内容
+fn recorded_body(capture: &Capture, chunked: bool) -> (Option<Cow<'_, [u8]>>, usize) {
+    let data = match capture.data() {
+        Some(data) if chunked => decode_chunked(data).map(Cow::Owned),
+        Some(data) => Some(Cow::Borrowed(data)),
+        None => None,
+    };
+    let len = data.as_ref().map_or(capture.len(), |d| d.len());
+    (data, len)
+}
+
+/// 改写消息体后按新长度设置 Content-Length
+fn length_rules(len: usize) -> HeaderRules {
+    HeaderRules {
+        set: [("Content-Length".to_string(), len.to_string())].into(),
+        remove: vec!["Transfer-Encoding".to_string()],
+        ..Default::default()
+    }
+}
+
+/// 镜像请求的请求头，不等待 100 Continue，发送后关闭连接
+fn mirror_head(head: &[u8], context: &HeaderContext) -> Vec<u8> {
+    let rules = HeaderRules {
+        set: [("Connection".to_string(), "close".to_string())].into(),
+        remove: vec!["Expect".to_string(), "Keep-Alive".to_string()],
+        ..Default::default()
+    };
+    rewrite_headers(head, &rules, context)
+}
+
+/// 按规则改写消息头，起始行及未涉及的消息头保持不变
+fn rewrite_headers(head: &[u8], rules: &HeaderRules, context: &HeaderContext) -> Vec<u8> {
+    if rules.is_empty() {
+        return head.to_vec();
+    }
+    let mut lines = head
+        .strip_suffix(b"\r\n\r\n")
+        .unwrap_or(head)
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    let mut data = Vec::with_capacity(head.len() + 128);
+    data.extend_from_slice(lines.next().unwrap_or_default());
+    data.extend_from_slice(b"\r\n");
+    for line in lines {
+        let name = line
+            .split(|b| *b == b':')
+            .next()
+            .unwrap_or_default()
+            .trim_ascii();
+        if contains_name(rules.remove.iter(), name) || contains_name(rules.set.keys(), name) {
+            continue;
         }
+        data.extend_from_slice(line);
+        data.extend_from_slice(b"\r\n");
     }
+    for (name, value) in rules.set.iter().chain(rules.add.iter()) {
+        data.extend_from_slice(format!("{}: {}\r\n", name, context.expand(value)).as_bytes());
+    }
+    data.extend_from_slice(b"\r\n");
+    data
+}
+
+fn contains_name<'a>(mut names: impl Iterator<Item = &'a String>, name: &[u8]) -> bool {
+    names.any(|n| n.as_bytes().eq_ignore_ascii_case(name))
+}
+
+/// 按规则替换请求行中的路径前缀，请求头其余部分及消息体保持不变
+///
+/// `GET /api/users HTTP/1.1` -> `GET /v2/users HTTP/1.1`
+fn rewrite_target(head: &[u8], rule: &RouteRule) -> Option<Vec<u8>> {
+    if !rule.forward.rewrite {
+        return None;
+    }
+    // 请求行 method SP request-target SP HTTP-version
+    let line_end = memchr::memmem::find(head, b"\r\n")?;
+    let line = &head[..line_end];
+    let start = memchr::memchr(b' ', line)? + 1;
+    let end = start + memchr::memchr(b' ', &line[start..])?;
+    let path = std::str::from_utf8(&line[start..end]).ok()?;
+    let new_path = rule.forward_path(path)?;
+    debug!("[{}] Original URL path: {}", rule.name, path);
+    debug!("[{}] Modified URL path: {}", rule.name, new_path);
+
+    let mut data = Vec::with_capacity(head.len() + new_path.len());
+    data.extend_from_slice(&head[..start]);
+    data.extend_from_slice(new_path.as_bytes());
+    data.extend_from_slice(&head[end..]);
+    Some(data)
 }
 
 fn is_http(data: &[u8], size: usize) -> bool {
@@ -229,53 +1128,424 @@ fn is_http(data: &[u8], size: usize) -> bool {
             || data.starts_with(b"HTTP/"))
 }
 
-fn parse_path(data: &[u8]) -> Option<&str> {
-    // 找到请求行的结束位置\r\n
-    let end = data.windows(2).position(|w| w == [b'\r', b'\n'])?;
-    let request_line = &data[..end];
-
-    // 按空格分割并过滤空字段
-    let mut parts = request_line
-        .split(|&b| b == b' ')
-        .filter(|part| !part.is_empty());
-
-    // 跳过方法（第一个字段），取路径（第二个字段）
-    parts.next()?; // 方法
-    let path_bytes = parts.next()?; // 路径
-    parts.next()?; // 协议版本（可选检查，确保存在）
-
-    std::str::from_utf8(path_bytes).ok()
-}
-
-pub(crate) async fn parse_http_header(stream: &TcpStream) -> Option<(String, String)> {
-    let mut buf = [0u8; 4096];
-    let n = stream.peek(&mut buf).await.ok()?;
-    if !is_http(buf.as_ref(), n) {
+/// 解析HTTP请求的 Host 及路径
+pub(crate) fn parse_http_header(data: &[u8]) -> Option<(String, String)> {
+    if !is_http(data, data.len()) {
         //非HTTP请求
         return None;
     }
 
-    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    // debug!("req_str {}", String::from_utf8_lossy(&buf[..n]));
+    // debug!("req_str {}", String::from_utf8_lossy(data));
 
-    let _status = req.parse(&buf[..n]).ok()?;
+    let _status = req.parse(data).ok()?;
     let path = req.path?.to_string();
-    let host = req
-        .headers
-        .iter()
-        .find(|h| h.name.eq_ignore_ascii_case("host"))
-        .and_then(|h| std::str::from_utf8(h.value).ok())?
-        .to_string();
+    let host = header_value(req.headers, "host")?.to_string();
     // debug!("req_path {path}");
     Some((host, path))
 }
 
-fn service_unavailable() -> Vec<u8> {
-    let response = b"HTTP/1.1 503 Service Unavailable\r\n\
-        Content-Type: text/plain\r\n\
-        Content-Length: 17\r\n\
-        Connection: close\r\n\r\n\
-        Service Unavailable";
-    response.to_vec()
+/// 连接目标失败时的响应
+pub(crate) fn gateway_error(e: &io::Error) -> Vec<u8> {
+    match e.kind() {
+        io::ErrorKind::TimedOut => simple_response("504 Gateway Timeout", ""),
+        _ => simple_response("502 Bad Gateway", ""),
+    }
+}
+
+pub(crate) fn simple_response(status: &str, headers: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, headers
+    )
+    .into_bytes()
+}
+
+/// 指定状态码及内容的响应
+pub(crate) fn error_response(status: u16, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// 状态码的原因短语
+pub(crate) fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        301 => "Moved Permanently",
+        302 => "Found",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+#[tokio::test]
+async fn test_copy_chunked_body() {
+    let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nGET / HTTP/1.1\r\n";
+    let (mut writer, reader) = tokio::io::duplex(16);
+    tokio::spawn(async move {
+        for chunk in body.chunks(3) {
+            writer.write_all(chunk).await.unwrap();
+        }
+    });
+    let mut conn = Conn::new(reader, &[]);
+    let mut out = Vec::new();
+    conn.copy_body(BodyLength::Chunked, &mut out).await.unwrap();
+    assert_eq!(
+        out,
+        b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\n"
+    );
+    // 下一个请求保留在缓冲区中
+    assert!(b"GET / HTTP/1.1\r\n".starts_with(&conn.buf));
+    // 读取时解码分块
+    let mut conn = Conn::new(tokio::io::duplex(1).0, body);
+    let data = conn.read_body(BodyLength::Chunked, 9).await.unwrap();
+    assert_eq!(data, b"Wikipedia");
+    let mut conn = Conn::new(tokio::io::duplex(1).0, body);
+    assert!(conn.read_body(BodyLength::Chunked, 8).await.is_err());
+}
+
+#[tokio::test]
+async fn test_buffer_body() {
+    let body = b"4\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\n";
+    assert_eq!(chunked_len(body).unwrap(), Some(body.len()));
+    assert_eq!(chunked_len(&body[..body.len() - 1]).unwrap(), None);
+    let (mut writer, reader) = tokio::io::duplex(16);
+    tokio::spawn(async move {
+        for chunk in body.chunks(3) {
+            writer.write_all(chunk).await.unwrap();
+        }
+    });
+    let mut conn = Conn::new(reader, &[]);
+    assert!(conn.buffer_body(BodyLength::Chunked, 64).await.unwrap());
+    let data = conn.read_body(BodyLength::Chunked, 64).await.unwrap();
+    assert_eq!(data, b"Wikipedia");
+    // 超过限制时已缓冲的部分仍可原样转发
+    let (mut writer, reader) = tokio::io::duplex(16);
+    tokio::spawn(async move { writer.write_all(body).await.unwrap() });
+    let mut conn = Conn::new(reader, &[]);
+    assert!(!conn.buffer_body(BodyLength::Chunked, 8).await.unwrap());
+    let mut out = Vec::new();
+    conn.copy_body(BodyLength::Chunked, &mut out).await.unwrap();
+    assert_eq!(out, body);
+}
+
+#[test]
+fn test_parse_message_framing() {
+    let req = parse_request(b"POST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\n").unwrap();
+    assert_eq!((req.body, req.keep_alive), (BodyLength::Fixed(5), true));
+    let req = parse_request(b"GET /a HTTP/1.0\r\n\r\n").unwrap();
+    assert_eq!((req.body, req.keep_alive), (BodyLength::Empty, false));
+    let req =
+        parse_request(b"POST /a HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").unwrap();
+    assert_eq!(req.body, BodyLength::Chunked);
+    assert!(parse_request(b"POST /a HTTP/1.1\r\nContent-Length: x\r\n\r\n").is_none());
+
+    let res = parse_response(b"HTTP/1.1 200 OK\r\n\r\n", "GET").unwrap();
+    assert_eq!((res.body, res.keep_alive), (BodyLength::UntilClose, false));
+    let res = parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n", "HEAD").unwrap();
+    assert_eq!((res.body, res.keep_alive), (BodyLength::Empty, true));
+    let res = parse_response(
+        b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n",
+        "GET",
+    )
+    .unwrap();
+    assert_eq!(
+        (res.status, res.body, res.keep_alive),
+        (304, BodyLength::Empty, false)
+    );
+}
+
+#[test]
+fn test_rewrite_target() {
+    let rule = RouteRule::new("example.com", "/api", "127.0.0.1:8080", "/v2");
+    // 超过16个请求头，且路径同时出现在其他请求头中
+    let mut head =
+        b"POST /api/users?q=/api HTTP/1.1\r\nReferer: http://example.com/api/x\r\n".to_vec();
+    for i in 0..20 {
+        head.extend_from_slice(format!("X-H{}: {}\r\n", i, i).as_bytes());
+    }
+    head.extend_from_slice(b"\r\n");
+    let rewritten = rewrite_target(&head, &rule).unwrap();
+    assert_eq!(&rewritten[..33], b"POST /v2/users?q=/api HTTP/1.1\r\nR");
+    assert_eq!(rewritten[32..], head[33..]);
+    // 请求头中的非UTF-8数据不影响改写
+    assert!(rewrite_target(b"GET /api HTTP/1.1\r\nX: \xff\r\n\r\n", &rule).is_some());
+    assert!(rewrite_target(b"GET /static HTTP/1.1\r\n\r\n", &rule).is_none());
+}
+
+#[test]
+fn test_parse_expect_continue() {
+    let req =
+        parse_request(b"PUT /a HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 1\r\n\r\n")
+            .unwrap();
+    assert!(req.expect_continue);
+    let req = parse_request(b"PUT /a HTTP/1.0\r\nExpect: 100-continue\r\n\r\n").unwrap();
+    assert!(!req.expect_continue);
+}
+
+#[test]
+fn test_rewrite_headers() {
+    let mut rule = RouteRule::new("example.com", "/api", "127.0.0.1:8080", "/v2");
+    rule.forward.rewrite_host = true;
+    let rules = &mut rule.forward.request_headers;
+    rules
+        .set
+        .insert("X-Forwarded-Host".to_string(), "{host}".to_string());
+    rules
+        .add
+        .insert("X-Forwarded-For".to_string(), "{client_ip}".to_string());
+    rules.remove.push("cookie".to_string());
+    let context = HeaderContext {
+        client_ip: "10.0.0.2".parse().unwrap(),
+        host: "example.com",
+        forward_host: "127.0.0.1:8080",
+    };
+    let head =
+        b"GET /a HTTP/1.1\r\nHost: example.com\r\nCookie: a=1\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n";
+    let head = rewrite_headers(
+        head,
+        &request_rules(&rule.forward, &rule.forward.host),
+        &context,
+    );
+    assert_eq!(
+        String::from_utf8(head).unwrap(),
+        "GET /a HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\nHost: 127.0.0.1:8080\r\n\
+         X-Forwarded-Host: example.com\r\nX-Forwarded-For: 10.0.0.2\r\n\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_connect_forward_alternates() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let alive = listener.local_addr().unwrap().to_string();
+    // 绑定后立即释放的端口，连接会被拒绝
+    let dead = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mut rule = RouteRule::new("*", "", &dead, "");
+    rule.forward.on_connect_fail.retries = 1;
+    rule.forward.on_connect_fail.backoff_ms = 1;
+    rule.forward.on_connect_fail.alternates = vec![dead.clone(), alive.clone()];
+    let mut upstreams = HashMap::new();
+    assert_eq!(
+        connect_forward(&mut upstreams, &rule, "", None)
+            .await
+            .unwrap(),
+        alive
+    );
+
+    rule.forward.on_connect_fail.alternates.clear();
+    assert!(
+        connect_forward(&mut HashMap::new(), &rule, "", None)
+            .await
+            .is_err()
+    );
+    // 上游地址池中连接失败的地址换到下一个上游
+    let addrs = vec![dead.clone(), alive.clone()];
+    let pool = crate::core::upstream::UpstreamPool::new(addrs, Default::default(), None);
+    rule.forward.pool = Arc::new(pool);
+    rule.forward.on_connect_fail.retries = 0;
+    for _ in 0..2 {
+        let addr = connect_forward(&mut HashMap::new(), &rule, "", None)
+            .await
+            .unwrap();
+        assert_eq!(addr, alive);
+    }
+}
+
+#[test]
+fn test_error_response() {
+    assert_eq!(
+        error_response(503, "busy"),
+        b"HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\
+          Connection: close\r\n\r\nbusy"
+    );
+    // 未知状态码没有原因短语
+    assert!(error_response(418, "teapot").starts_with(b"HTTP/1.1 418 \r\n"));
+}
+
+/// 测试用的代理连接，客户端按 HTTP 代理方式发送请求
+#[cfg(test)]
+fn spawn_proxy(
+    rules: Vec<RouteRule>,
+) -> (
+    Conn<tokio::io::DuplexStream>,
+    tokio::task::JoinHandle<Result<()>>,
+) {
+    let (client, proxy) = tokio::io::duplex(64 * 1024);
+    let rules = Arc::new(tokio::sync::RwLock::new(rules));
+    let route_engine = Arc::new(RouteEngine { rules });
+    let client_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+    let handle = tokio::spawn(forward_handle(
+        proxy,
+        client_addr,
+        &[],
+        Origin::Proxy,
+        route_engine,
+    ));
+    (Conn::new(client, &[]), handle)
+}
+
+#[tokio::test]
+async fn test_retry_closed_upstream() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // 每个连接只响应一个请求，之后关闭连接
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = Conn::new(stream, &[]);
+            conn.read_head().await.unwrap();
+            let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+            conn.stream.write_all(response).await.unwrap();
+        }
+    });
+    let (mut client, _proxy) = spawn_proxy(Vec::new());
+    for path in ["/a", "/b"] {
+        let request = format!("GET http://{addr}{path} HTTP/1.1\r\nHost: {addr}\r\n\r\n");
+        client.stream.write_all(request.as_bytes()).await.unwrap();
+        let head = client.read_head().await.unwrap().unwrap();
+        let response = parse_response(&head, "GET").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(client.read_body(response.body, 16).await.unwrap(), b"ok");
+    }
+}
+
+#[tokio::test]
+async fn test_fault_reset() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut rule = RouteRule::new("*", "", "127.0.0.1:9", "");
+    rule.forward.faults.reset = Some(config::ResetFault { percent: 100.0 });
+    let rules = Arc::new(tokio::sync::RwLock::new(vec![rule]));
+    let route_engine = Arc::new(RouteEngine { rules });
+    tokio::spawn(async move {
+        let (stream, client_addr) = listener.accept().await.unwrap();
+        forward_handle(stream, client_addr, &[], Origin::Proxy, route_engine).await
+    });
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = Vec::new();
+    let err = client.read_to_end(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[tokio::test]
+async fn test_replay_body_too_large() {
+    let mut rule = RouteRule::new("*", "", "127.0.0.1:9", "");
+    let record = config::Record {
+        mode: config::RecordMode::Replay,
+        dir: std::env::temp_dir().to_string_lossy().to_string(),
+        format: Default::default(),
+        match_body: true,
+    };
+    let recorder = crate::core::record::Recorder::new(&record, "replay-too-large");
+    rule.forward.record = Some(Arc::new(recorder));
+    let (mut client, proxy) = spawn_proxy(vec![rule]);
+    let request = format!(
+        "POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\nContent-Length: {}\r\n\r\n",
+        MAX_RECORD_BODY + 1
+    );
+    client.stream.write_all(request.as_bytes()).await.unwrap();
+    let head = client.read_head().await.unwrap().unwrap();
+    assert!(head.starts_with(b"HTTP/1.1 413 Payload Too Large\r\n"));
+    assert!(proxy.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_upgrade_absolute_uri() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // 上游只在收到 Connection: upgrade 时切换协议，之后原样返回收到的数据
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = Conn::new(stream, &[]);
+        let head = conn.read_head().await.unwrap().unwrap();
+        let head = String::from_utf8_lossy(&head).to_ascii_lowercase();
+        if !head.contains("\r\nconnection: upgrade\r\n") {
+            conn.stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            return;
+        }
+        conn.stream
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+        let mut data = [0u8; 4];
+        conn.stream.read_exact(&mut data).await.unwrap();
+        conn.stream.write_all(&data).await.unwrap();
+    });
+    let (mut client, _proxy) = spawn_proxy(Vec::new());
+    let request = format!(
+        "GET http://{addr}/ws HTTP/1.1\r\nHost: {addr}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n"
+    );
+    client.stream.write_all(request.as_bytes()).await.unwrap();
+    let head = client.read_head().await.unwrap().unwrap();
+    assert!(head.starts_with(b"HTTP/1.1 101 "));
+    client.stream.write_all(b"ping").await.unwrap();
+    let mut data = [0u8; 4];
+    client.stream.read_exact(&mut data).await.unwrap();
+    assert_eq!(&data, b"ping");
+}
+
+#[tokio::test]
+async fn test_expect_continue_ignored() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // 上游忽略 Expect，直接等待消息体
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = Conn::new(stream, &[]);
+        let head = conn.read_head().await.unwrap().unwrap();
+        let request = parse_request(&head).unwrap();
+        let body = conn.read_body(request.body, 16).await.unwrap();
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+        conn.stream.write_all(response.as_bytes()).await.unwrap();
+        conn.stream.write_all(&body).await.unwrap();
+    });
+    let (mut client, _proxy) = spawn_proxy(Vec::new());
+    let request = format!(
+        "POST http://{addr}/ HTTP/1.1\r\nHost: {addr}\r\nContent-Length: 5\r\n\
+         Expect: 100-continue\r\n\r\n"
+    );
+    client.stream.write_all(request.as_bytes()).await.unwrap();
+    // 客户端等不到 100 后发送消息体
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.stream.write_all(b"hello").await.unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(5), async {
+        let head = client.read_head().await.unwrap().unwrap();
+        let response = parse_response(&head, "POST").unwrap();
+        (
+            response.status,
+            client.read_body(response.body, 16).await.unwrap(),
+        )
+    });
+    assert_eq!(response.await.unwrap(), (200, b"hello".to_vec()));
 }
@@ -0,0 +1,287 @@
+use crate::core::config::User;
+use crate::core::http::{
+    MAX_HEAD_SIZE, MAX_HEADERS, Origin, forward_handle, gateway_error, simple_response,
+};
+use crate::core::route::RouteEngine;
+use crate::core::socks::{connect_target, relay};
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::BytesMut;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+/// 转发时需要去掉的代理相关请求头
+const PROXY_HEADERS: [&str; 4] = [
+    "proxy-connection",
+    "proxy-authorization",
+    "connection",
+    "keep-alive",
+];
+
+/// 处理HTTP代理请求，支持 CONNECT 隧道和绝对URI形式的普通请求
+///
+/// 普通请求支持 keep-alive，每个请求单独匹配路由规则
+pub(crate) async fn handle_client(
+    mut client: TcpStream,
+    route_engine: Arc<RouteEngine>,
+    users: Arc<Vec<User>>,
+) -> Result<()> {
+    let Some((buf, head_len)) = read_head(&mut client).await? else {
+        client
+            .write_all(&simple_response("400 Bad Request", ""))
+            .await?;
+        return Err(anyhow!("Invalid HTTP proxy request"));
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let parsed = matches!(
+        req.parse(&buf[..head_len]),
+        Ok(httparse::Status::Complete(_))
+    );
+    let (true, Some(method), Some(target)) = (parsed, req.method, req.path) else {
+        client
+            .write_all(&simple_response("400 Bad Request", ""))
+            .await?;
+        return Err(anyhow!("Invalid HTTP proxy request"));
+    };
+
+    if !users.is_empty() && !authorized(req.headers, &users) {
+        let challenge = "Proxy-Authenticate: Basic realm=\"proxy-forward\"\r\n";
+        let response = simple_response("407 Proxy Authentication Required", challenge);
+        client.write_all(&response).await?;
+        return Err(anyhow!("Proxy authentication required"));
+    }
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let address = target.to_string();
+        return tunnel(client, &address, &buf[head_len..], route_engine).await;
+    }
+
+    let client_addr = client.peer_addr()?;
+    debug!("HTTP proxy connection from {}", client_addr);
+    forward_handle(client, client_addr, &buf, Origin::Proxy, route_engine).await
+}
+
+/// 建立 CONNECT 隧道，隧道内的数据与SOCKS连接一样处理
+async fn tunnel(
+    mut client: TcpStream,
+    address: &str,
+    early_data: &[u8],
+    route_engine: Arc<RouteEngine>,
+) -> Result<()> {
+    let server = match connect_target(address).await {
+        Ok(s) => s,
+        Err(e) => {
+            client.write_all(&gateway_error(&e)).await?;
+            return Err(anyhow!("Connect to {} failed: {}", address, e));
+        }
+    };
+    client
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+    debug!(
+        "[{}] HTTP tunnel to {}",
+        route_engine.rule_name_by_host(address).await,
+        address
+    );
+    relay(client, server, address, early_data, route_engine).await
+}
+
+/// 读取请求头，返回缓冲区及请求头长度，请求头过长或连接关闭时返回 None
+pub(crate) async fn read_head(client: &mut TcpStream) -> Result<Option<(BytesMut, usize)>> {
+    let mut buf = BytesMut::with_capacity(4096);
+    loop {
+        if let Some(pos) = memchr::memmem::find(&buf, b"\r\n\r\n") {
+            return Ok(Some((buf, pos + 4)));
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Ok(None);
+        }
+        if client.read_buf(&mut buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// 校验 Proxy-Authorization: Basic 认证信息
+fn authorized(headers: &[httparse::Header], users: &[User]) -> bool {
+    let Some(header) = headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("proxy-authorization"))
+    else {
+        return false;
+    };
+    let value = String::from_utf8_lossy(header.value);
+    let Some(("Basic" | "basic", encoded)) = value.trim().split_once(' ') else {
+        return false;
+    };
+    let Ok(decoded) = STANDARD.decode(encoded.trim()) else {
+        return false;
+    };
+    let credentials = String::from_utf8_lossy(&decoded);
+    let Some((username, password)) = credentials.split_once(':') else {
+        return false;
+    };
+    let passed = users
+        .iter()
+        .any(|u| u.username == username && u.password == password);
+    if !passed {
+        warn!("Proxy authentication failed for user: {}", username);
+    }
+    passed
+}
+
+/// 解析绝对URI，返回 authority 及 origin-form 路径
+///
+/// `http://example.com:8080/api?a=1` -> (`example.com:8080`, `/api?a=1`)
+pub(crate) fn parse_absolute_uri(uri: &str) -> Option<(&str, String)> {
+    let (scheme, rest) = uri.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") {
+        return None;
+    }
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+    Some((authority, path))
+}
+
+/// authority 没有端口时添加默认端口
+fn with_default_port(authority: &str, port: u16) -> String {
+    let has_port = match authority.rfind(']') {
+        // IPv6 [::1]:8080
+        Some(i) => authority[i..].contains(':'),
+        None => authority.contains(':'),
+    };
+    if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:{}", authority, port)
+    }
+}
+
+/// 将绝对URI形式的请求转换为 origin-form，去掉代理相关请求头
+fn build_origin_head(
+    method: &str,
+    path: &str,
+    version: u8,
+    headers: &[httparse::Header],
+    authority: &str,
+) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.{}\r\n", method, path, version).into_bytes();
+    let mut has_host = false;
+    // 协议升级需要保留 Connection: upgrade，其他连接选项只作用于客户端到代理
+    let upgrade = headers
+        .iter()
+        .any(|h| h.name.eq_ignore_ascii_case("upgrade"));
+    for header in headers {
+        let name = header.name.to_ascii_lowercase();
+        if PROXY_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        has_host |= name == "host";
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+    if !has_host {
+        head.extend_from_slice(format!("Host: {}\r\n", authority).as_bytes());
+    }
+    if upgrade {
+        head.extend_from_slice(b"Connection: upgrade\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// 将代理请求头转换为 origin-form
+///
+/// 返回目标地址、转换后的请求头、路径及 authority
+pub(crate) fn origin_form(head: &[u8]) -> Option<(String, Vec<u8>, String, String)> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(head).ok()?;
+    let (authority, path) = parse_absolute_uri(req.path?)?;
+    let head = build_origin_head(req.method?, &path, req.version?, req.headers, authority);
+    Some((
+        with_default_port(authority, 80),
+        head,
+        path,
+        authority.to_string(),
+    ))
+}
+
+#[test]
+fn test_parse_absolute_uri() {
+    assert_eq!(
+        parse_absolute_uri("http://example.com:8080/api?a=1"),
+        Some(("example.com:8080", "/api?a=1".to_string()))
+    );
+    assert_eq!(
+        parse_absolute_uri("HTTP://example.com"),
+        Some(("example.com", "/".to_string()))
+    );
+    assert_eq!(
+        parse_absolute_uri("http://example.com?a=1"),
+        Some(("example.com", "/?a=1".to_string()))
+    );
+    assert_eq!(parse_absolute_uri("https://example.com/"), None);
+    assert_eq!(parse_absolute_uri("/api"), None);
+    assert_eq!(parse_absolute_uri("http:///api"), None);
+    assert_eq!(with_default_port("example.com", 80), "example.com:80");
+    assert_eq!(with_default_port("[::1]", 80), "[::1]:80");
+    assert_eq!(with_default_port("[::1]:8080", 80), "[::1]:8080");
+}
+
+#[test]
+fn test_build_origin_head() {
+    let raw =
+        b"GET http://example.com/a HTTP/1.1\r\nProxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n";
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(raw).unwrap();
+    let head = build_origin_head("GET", "/a", 1, req.headers, "example.com");
+    assert_eq!(
+        String::from_utf8(head).unwrap(),
+        "GET /a HTTP/1.1\r\nAccept: */*\r\nHost: example.com\r\n\r\n"
+    );
+    let raw = b"GET http://example.com/ws HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\n\
+                Upgrade: websocket\r\n\r\n";
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(raw).unwrap();
+    let head = build_origin_head("GET", "/ws", 1, req.headers, "example.com");
+    assert_eq!(
+        String::from_utf8(head).unwrap(),
+        "GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nHost: example.com\r\nConnection: upgrade\r\n\r\n"
+    );
+}
+
+#[test]
+fn test_authorized() {
+    let users = vec![User {
+        username: "dev".to_string(),
+        password: "secret".to_string(),
+    }];
+    let header = |value: &'static [u8]| httparse::Header {
+        name: "Proxy-Authorization",
+        value,
+    };
+    // dev:secret
+    assert!(authorized(&[header(b"Basic ZGV2OnNlY3JldA==")], &users));
+    // dev:bad
+    assert!(!authorized(&[header(b"Basic ZGV2OmJhZA==")], &users));
+    assert!(!authorized(&[], &users));
+}
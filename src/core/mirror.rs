@@ -0,0 +1,108 @@
+use crate::core::capture::Capture;
+use crate::core::config::Mirror;
+use crate::core::http::read_status;
+use crate::core::socks::connect_target;
+use anyhow::Result;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+
+/// 镜像请求消息体的最大长度，超过时不发送镜像请求
+const MAX_MIRROR_BODY: usize = 1024 * 1024;
+/// 镜像请求的超时时间
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 待发送的镜像请求，转发原请求的消息体时同时复制一份
+pub(crate) struct MirrorRequest {
+    addr: String,
+    log_response: bool,
+    rule_name: String,
+    /// 请求行，用于日志
+    target: String,
+    head: Vec<u8>,
+    /// 转发原请求时复制的消息体
+    pub(crate) body: Capture,
+}
+
+impl MirrorRequest {
+    /// 按采样百分比决定是否镜像请求
+    pub(crate) fn sample(
+        mirror: &Mirror,
+        rule_name: &str,
+        target: String,
+        head: Vec<u8>,
+    ) -> Option<Self> {
+        if !rand::random_bool(mirror.percent / 100.0) {
+            return None;
+        }
+        Some(Self {
+            addr: mirror.addr.clone(),
+            log_response: mirror.log_response,
+            rule_name: rule_name.to_string(),
+            target,
+            head,
+            body: Capture::new(MAX_MIRROR_BODY),
+        })
+    }
+
+    /// 在后台发送镜像请求，不等待结果
+    pub(crate) fn spawn(self) {
+        if self.body.overflow() {
+            debug!(
+                "[{}] Mirror {} skipped, body larger than {} bytes",
+                self.rule_name, self.target, MAX_MIRROR_BODY
+            );
+            return;
+        }
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let result = tokio::time::timeout(MIRROR_TIMEOUT, self.send())
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+            let elapsed = start.elapsed().as_millis();
+            match result {
+                Ok(status) if self.log_response => info!(
+                    "[{}] Mirror {} -> {} {} ({}ms)",
+                    self.rule_name, self.target, self.addr, status, elapsed
+                ),
+                Ok(status) => debug!(
+                    "[{}] Mirror {} -> {} {} ({}ms)",
+                    self.rule_name, self.target, self.addr, status, elapsed
+                ),
+                Err(e) if self.log_response => warn!(
+                    "[{}] Mirror {} -> {} failed: {}",
+                    self.rule_name, self.target, self.addr, e
+                ),
+                Err(e) => debug!(
+                    "[{}] Mirror {} -> {} failed: {}",
+                    self.rule_name, self.target, self.addr, e
+                ),
+            }
+        });
+    }
+
+    /// 发送请求并读取响应状态，响应内容被丢弃
+    async fn send(&self) -> Result<u16> {
+        let mut stream = connect_target(&self.addr).await?;
+        stream.write_all(&self.head).await?;
+        stream
+            .write_all(self.body.data().unwrap_or_default())
+            .await?;
+        read_status(&mut stream).await
+    }
+}
+
+#[test]
+fn test_sample() {
+    let mirror = Mirror {
+        addr: "127.0.0.1:1".to_string(),
+        percent: 100.0,
+        log_response: false,
+    };
+    assert!(MirrorRequest::sample(&mirror, "test", "GET /".to_string(), Vec::new()).is_some());
+    let mirror = Mirror {
+        percent: 0.0,
+        ..mirror
+    };
+    assert!(MirrorRequest::sample(&mirror, "test", "GET /".to_string(), Vec::new()).is_none());
+}
@@ -0,0 +1,428 @@
+use crate::core::config::{CONFIG_FILE, Mitm};
+use crate::core::fault::Reset;
+use crate::core::http::{Origin, forward_handle};
+use crate::core::route::{RouteEngine, split_host_port};
+use anyhow::{Result, anyhow};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose, SerialNumber,
+};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use time::{Duration, OffsetDateTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::OnceCell;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    CryptoProvider, verify_tls12_signature, verify_tls13_signature,
+};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+};
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{LazyConfigAcceptor, TlsConnector, client};
+use tracing::{debug, info, warn};
+
+/// CA 证书及私钥文件，与配置文件在同一目录
+const CA_CERT_FILE: &str = "proxy-forward-ca.pem";
+const CA_KEY_FILE: &str = "proxy-forward-ca.key";
+/// 缓存的主机证书数量上限，超过时清空
+const MAX_CACHED_CERTS: usize = 1024;
+
+static AUTHORITY: OnceCell<CertAuthority> = OnceCell::const_new();
+
+/// 本地 CA，为拦截的主机签发证书
+pub(crate) struct CertAuthority {
+    issuer: Issuer<'static, KeyPair>,
+    cert: CertificateDer<'static>,
+    cache: Mutex<HashMap<String, Arc<ServerConfig>>>,
+}
+
+impl CertAuthority {
+    /// 读取目录中的 CA 证书及私钥，不存在时生成
+    async fn load_or_create(dir: &Path) -> Result<Self> {
+        let (cert_path, key_path) = (dir.join(CA_CERT_FILE), dir.join(CA_KEY_FILE));
+        if !tokio::fs::try_exists(&cert_path).await? {
+            let key = KeyPair::generate()?;
+            let mut params = CertificateParams::default();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "proxy-forward CA");
+            params
+                .distinguished_name
+                .push(DnType::OrganizationName, "proxy-forward");
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.key_usages = vec![
+                KeyUsagePurpose::KeyCertSign,
+                KeyUsagePurpose::CrlSign,
+                KeyUsagePurpose::DigitalSignature,
+            ];
+            let now = OffsetDateTime::now_utc();
+            params.not_before = now - Duration::days(1);
+            params.not_after = now + Duration::days(3650);
+            let cert = params.self_signed(&key)?;
+            // 私钥文件创建时只有所有者可读写，上次生成中断时遗留的私钥先删除
+            match tokio::fs::remove_file(&key_path).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options.open(&key_path).await?;
+            file.write_all(key.serialize_pem().as_bytes()).await?;
+            file.flush().await?;
+            tokio::fs::write(&cert_path, cert.pem()).await?;
+            info!(
+                "Generated CA certificate {}, trust it on clients to intercept TLS",
+                cert_path.display()
+            );
+        }
+        let cert_pem = tokio::fs::read_to_string(&cert_path).await?;
+        let key_pem = tokio::fs::read_to_string(&key_path).await?;
+        let key = KeyPair::from_pem(&key_pem)?;
+        Ok(Self {
+            issuer: Issuer::from_ca_cert_pem(&cert_pem, key)?,
+            cert: CertificateDer::from_pem_slice(cert_pem.as_bytes())?,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// 返回使用主机证书的 TLS 配置，证书按主机名缓存
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(config) = cache.get(host) {
+            return Ok(config.clone());
+        }
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![host.to_string()])?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        params.serial_number = Some(SerialNumber::from(rand::random::<[u8; 16]>().to_vec()));
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(365);
+        let cert = params.signed_by(&key, &self.issuer)?;
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone(), self.cert.clone()], key)?;
+        // 解密后按 HTTP/1.1 处理
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let config = Arc::new(config);
+        if cache.len() >= MAX_CACHED_CERTS {
+            cache.clear();
+        }
+        cache.insert(host.to_string(), config.clone());
+        Ok(config)
+    }
+}
+
+/// 本地 CA，第一次使用时读取或生成
+async fn authority() -> Result<&'static CertAuthority> {
+    AUTHORITY
+        .get_or_try_init(|| {
+            let dir = Path::new(CONFIG_FILE).parent().unwrap_or(Path::new(""));
+            CertAuthority::load_or_create(dir)
+        })
+        .await
+}
+
+/// 解密客户端的 TLS 连接，请求按规则改写后转发，原始地址重新使用 TLS 连接
+pub(crate) async fn intercept(
+    client: TcpStream,
+    server: TcpStream,
+    address: &str,
+    early_data: &[u8],
+    mitm: Mitm,
+    route_engine: Arc<RouteEngine>,
+) -> Result<()> {
+    let client_addr = client.peer_addr()?;
+    let client = Rewind::new(early_data, client);
+    let start = LazyConfigAcceptor::new(Acceptor::default(), client).await?;
+    // 客户端没有发送 SNI 时使用目标地址的主机名
+    let server_name = start
+        .client_hello()
+        .server_name()
+        .unwrap_or(split_host_port(address).0)
+        .to_string();
+    let config = authority().await?.server_config(&server_name)?;
+    let client = start
+        .into_stream(config)
+        .await
+        .map_err(|e| anyhow!("TLS handshake with client for {} failed: {}", address, e))?;
+    debug!("Intercept TLS connection to {} ({})", address, server_name);
+    let upstream = UpstreamTls {
+        server_name,
+        verify: mitm.verify,
+    };
+    // 原始地址握手失败时不影响转发到其他地址的请求，需要时再重新连接
+    let server = match connect(server, &upstream).await {
+        Ok(server) => Some(Upstream::Tls(Box::new(server))),
+        Err(e) => {
+            warn!("TLS handshake with {} failed: {}", address, e);
+            None
+        }
+    };
+    let origin = Origin::Intercept {
+        addr: address.to_string(),
+        server,
+        upstream,
+        mitm,
+    };
+    forward_handle(client, client_addr, &[], origin, route_engine).await
+}
+
+/// 连接上游时的 TLS 设置
+#[derive(Debug, Clone)]
+pub(crate) struct UpstreamTls {
+    /// SNI 及验证证书使用的主机名
+    pub(crate) server_name: String,
+    pub(crate) verify: bool,
+}
+
+/// 与上游完成 TLS 握手
+pub(crate) async fn connect(
+    stream: TcpStream,
+    tls: &UpstreamTls,
+) -> io::Result<client::TlsStream<TcpStream>> {
+    let server_name = ServerName::try_from(tls.server_name.clone())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    TlsConnector::from(client_config(tls.verify))
+        .connect(server_name, stream)
+        .await
+}
+
+fn client_config(verify: bool) -> Arc<ClientConfig> {
+    static VERIFIED: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    static UNVERIFIED: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    let build = || {
+        let builder = ClientConfig::builder();
+        let mut config = if verify {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        } else {
+            let provider = builder.crypto_provider().clone();
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerifier(provider)))
+                .with_no_client_auth()
+        };
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Arc::new(config)
+    };
+    let config = if verify { &VERIFIED } else { &UNVERIFIED };
+    config.get_or_init(build).clone()
+}
+
+/// 不验证上游证书，只检查握手签名
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// 上游连接，拦截 TLS 时重新使用 TLS 连接
+pub(crate) enum Upstream {
+    Plain(TcpStream),
+    Tls(Box<client::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Upstream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upstream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Upstream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Upstream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Upstream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Upstream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upstream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Upstream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upstream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Upstream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// 先读取握手时已读取的数据，再读取原始连接
+struct Rewind<S> {
+    early_data: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    fn new(early_data: &[u8], inner: S) -> Self {
+        Self {
+            early_data: early_data.to_vec(),
+            inner,
+        }
+    }
+}
+
+impl<S: Reset> Reset for Rewind<S> {
+    fn reset(&self) -> io::Result<()> {
+        self.inner.reset()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.early_data.is_empty() {
+            let n = self.early_data.len().min(buf.remaining());
+            buf.put_slice(&self.early_data[..n]);
+            self.early_data.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_server_config() {
+    use tokio::io::AsyncReadExt;
+    let dir = std::env::temp_dir().join(format!("proxy-forward-mitm-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let authority = CertAuthority::load_or_create(&dir).await.unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = std::fs::metadata(dir.join(CA_KEY_FILE)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+    // 再次读取已保存的 CA
+    let reloaded = CertAuthority::load_or_create(&dir).await.unwrap();
+    assert_eq!(authority.cert, reloaded.cert);
+    let config = reloaded.server_config("api.test").unwrap();
+    assert!(Arc::ptr_eq(
+        &config,
+        &reloaded.server_config("api.test").unwrap()
+    ));
+
+    // 信任 CA 的客户端可以完成握手
+    let mut roots = RootCertStore::empty();
+    roots.add(authority.cert.clone()).unwrap();
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let (client, server) = tokio::io::duplex(16 * 1024);
+    let acceptor = tokio_rustls::TlsAcceptor::from(config);
+    let server = tokio::spawn(async move {
+        let mut stream = acceptor.accept(server).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+    let name = ServerName::try_from("api.test").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(client_config))
+        .connect(name, client)
+        .await
+        .unwrap();
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data, b"hello");
+    server.await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
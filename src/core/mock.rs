@@ -0,0 +1,215 @@
+use crate::core::config;
+use crate::core::http::reason;
+use crate::core::route::RouteRequest;
+use anyhow::anyhow;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// 模拟响应
+#[derive(Debug, Clone)]
+pub(crate) struct Mock {
+    pub(crate) status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+    /// 返回响应前等待的时间
+    pub(crate) delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+enum Body {
+    Inline(String),
+    File(String),
+}
+
+impl TryFrom<&config::Mock> for Mock {
+    type Error = anyhow::Error;
+
+    fn try_from(mock: &config::Mock) -> anyhow::Result<Self> {
+        if !(200..600).contains(&mock.status) {
+            return Err(anyhow!("Invalid mock status: {}", mock.status));
+        }
+        let body = match (&mock.body, &mock.file) {
+            (Some(_), Some(_)) => return Err(anyhow!("Mock body and file are exclusive")),
+            (_, Some(file)) => Body::File(file.clone()),
+            (body, None) => Body::Inline(body.clone().unwrap_or_default()),
+        };
+        Ok(Self {
+            status: mock.status,
+            headers: mock.headers.clone().into_iter().collect(),
+            body,
+            delay: Duration::from_millis(mock.delay_ms),
+        })
+    }
+}
+
+impl Mock {
+    /// 按请求生成响应，内容文件读取失败时返回错误
+    pub(crate) async fn render(
+        &self,
+        request: &RouteRequest<'_>,
+        client_ip: IpAddr,
+        keep_alive: bool,
+    ) -> io::Result<Vec<u8>> {
+        let (body, content_type) = match &self.body {
+            Body::Inline(body) => (
+                expand(body, request, client_ip).into_bytes(),
+                guess_content_type(Some(body), None),
+            ),
+            // 只替换文本文件中的变量，二进制文件原样返回
+            Body::File(file) => match String::from_utf8(tokio::fs::read(file).await?) {
+                Ok(text) => (
+                    expand(&text, request, client_ip).into_bytes(),
+                    guess_content_type(Some(&text), Some(file)),
+                ),
+                Err(e) => (e.into_bytes(), guess_content_type(None, Some(file))),
+            },
+        };
+        let mut data = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        let mut has_content_type = false;
+        for (name, value) in &self.headers {
+            has_content_type |= name.eq_ignore_ascii_case("content-type");
+            data.push_str(&format!(
+                "{}: {}\r\n",
+                name,
+                expand(value, request, client_ip)
+            ));
+        }
+        if !has_content_type && !body.is_empty() {
+            data.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        data.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        if !keep_alive {
+            data.push_str("Connection: close\r\n");
+        }
+        data.push_str("\r\n");
+        let mut data = data.into_bytes();
+        if !request.method.eq_ignore_ascii_case("HEAD") {
+            data.extend_from_slice(&body);
+        }
+        Ok(data)
+    }
+}
+
+/// 根据文件扩展名或内容推断类型，body 为 None 表示不是文本
+fn guess_content_type(body: Option<&str>, file: Option<&str>) -> &'static str {
+    let extension = file
+        .and_then(|f| f.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("json") => "application/json",
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("js") => "application/javascript",
+        Some("css") => "text/css",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        _ => match body {
+            Some(body) if body.trim_start().starts_with(['{', '[']) => "application/json",
+            Some(_) => "text/plain; charset=utf-8",
+            None => "application/octet-stream",
+        },
+    }
+}
+
+/// 替换模板中的变量，未知的变量保持不变
+fn expand(template: &str, request: &RouteRequest, client_ip: IpAddr) -> String {
+    let mut data = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        data.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after
+            .find('}')
+            .and_then(|end| Some((end, lookup(&after[..end], request, client_ip)?)));
+        match value {
+            Some((end, value)) => {
+                data.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                data.push('{');
+                rest = after;
+            }
+        }
+    }
+    data.push_str(rest);
+    data
+}
+
+fn lookup<'a>(name: &'a str, request: &RouteRequest<'a>, client_ip: IpAddr) -> Option<String> {
+    let value = match name {
+        "method" => request.method,
+        "host" => request.host,
+        "path" => request.path.split(['?', '#']).next().unwrap_or_default(),
+        "client_ip" => return Some(client_ip.to_string()),
+        _ => {
+            if let Some(name) = name.strip_prefix("query.") {
+                request.query(name).next().unwrap_or_default()
+            } else if let Some(name) = name.strip_prefix("header.") {
+                request
+                    .headers
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .map_or("", |(_, v)| v.as_str())
+            } else {
+                return None;
+            }
+        }
+    };
+    Some(value.to_string())
+}
+
+#[tokio::test]
+async fn test_render() {
+    let mock = Mock::try_from(&config::Mock {
+        status: 201,
+        headers: [("X-Id".to_string(), "{query.id}".to_string())].into(),
+        body: Some(
+            r#"{"path":"{path}","id":"{query.id}","user":"{header.x-user}","x":"{unknown}"}"#
+                .to_string(),
+        ),
+        ..Default::default()
+    })
+    .unwrap();
+    let headers = [("X-User".to_string(), "alice".to_string())];
+    let request = RouteRequest {
+        method: "POST",
+        host: "api.test",
+        path: "/users?id=7",
+        headers: &headers,
+    };
+    let ip = "10.0.0.1".parse().unwrap();
+    let response = String::from_utf8(mock.render(&request, ip, false).await.unwrap()).unwrap();
+    let body = r#"{"path":"/users","id":"7","user":"alice","x":"{unknown}"}"#;
+    assert_eq!(
+        response,
+        format!(
+            "HTTP/1.1 201 Created\r\nX-Id: 7\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    );
+    let file = config::Mock {
+        body: Some(String::new()),
+        file: Some("mock.json".to_string()),
+        ..Default::default()
+    };
+    assert!(Mock::try_from(&file).is_err());
+
+    // 二进制文件原样返回
+    let path = std::env::temp_dir().join(format!("proxy-forward-mock-{}.png", std::process::id()));
+    let png = b"\x89PNG\r\n\x1a\n{path}\xff";
+    std::fs::write(&path, png).unwrap();
+    let file = Mock::try_from(&config::Mock {
+        file: Some(path.to_string_lossy().to_string()),
+        ..Default::default()
+    })
+    .unwrap();
+    let response = file.render(&request, ip, true).await.unwrap();
+    let head = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 15\r\n\r\n";
+    assert_eq!(response, [&head[..], png].concat());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,4 +1,17 @@
 pub(crate) mod socks;
 pub(crate) mod route;
 pub(crate) mod http;
-pub(crate) mod config;
\ No newline at end of file
+pub(crate) mod http_proxy;
+pub(crate) mod codec;
+pub(crate) mod config;
+pub(crate) mod udp;
+pub(crate) mod reload;
+pub(crate) mod admin;
+pub(crate) mod upstream;
+pub(crate) mod mirror;
+pub(crate) mod response;
+pub(crate) mod mock;
+pub(crate) mod capture;
+pub(crate) mod record;
+pub(crate) mod fault;
+pub(crate) mod mitm;
\ No newline at end of file
@@ -0,0 +1,619 @@
+use crate::core::config::{self, RecordFormat, RecordMode};
+use crate::core::http::{MAX_HEADERS, reason};
+use crate::core::route::RouteRequest;
+use crate::core::upstream::fnv1a;
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, warn};
+
+/// 录制及回放时消息体的最大长度，超过时不录制消息体
+pub(crate) const MAX_RECORD_BODY: usize = 1024 * 1024;
+
+/// 录制转发的请求及响应，或回放录制的响应
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    mode: RecordMode,
+    format: RecordFormat,
+    match_body: bool,
+    path: PathBuf,
+    rule_name: String,
+    /// 串行写入录制文件
+    write_lock: tokio::sync::Mutex<()>,
+    /// 回放时缓存的录制内容及文件修改时间
+    cache: Mutex<Option<(SystemTime, Arc<Vec<Entry>>)>>,
+}
+
+/// 录制的请求及响应，使用 HAR 1.2 的 entry 格式
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: u64,
+    request: Request,
+    response: Response,
+    #[serde(default)]
+    cache: BTreeMap<String, String>,
+    #[serde(default)]
+    timings: Timings,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    method: String,
+    url: String,
+    http_version: String,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    post_data: Option<Content>,
+    headers_size: i64,
+    body_size: i64,
+    /// 请求消息体的哈希
+    #[serde(rename = "_bodyHash", default)]
+    body_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL", default)]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+/// 消息体，非 UTF-8 内容使用 base64 编码，超过最大长度时没有 text
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    #[serde(default)]
+    size: i64,
+    #[serde(default)]
+    mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Timings {
+    send: i64,
+    wait: i64,
+    receive: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Har {
+    log: Log,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Log {
+    version: String,
+    creator: NameValue,
+    entries: Vec<Entry>,
+}
+
+impl Content {
+    fn new(data: Option<&[u8]>, size: usize, mime_type: &str) -> Self {
+        let (text, encoding) = match data.map(std::str::from_utf8) {
+            Some(Ok(text)) => (Some(text.to_string()), None),
+            Some(Err(_)) => (data.map(|d| STANDARD.encode(d)), Some("base64".to_string())),
+            None => (None, None),
+        };
+        Self {
+            size: size as i64,
+            mime_type: mime_type.to_string(),
+            text,
+            encoding,
+        }
+    }
+
+    fn data(&self) -> Vec<u8> {
+        let text = self.text.as_deref().unwrap_or_default();
+        match self.encoding.as_deref() {
+            Some("base64") => STANDARD.decode(text).unwrap_or_default(),
+            _ => text.as_bytes().to_vec(),
+        }
+    }
+}
+
+impl Recorder {
+    pub(crate) fn new(record: &config::Record, rule_name: &str) -> Self {
+        // 规则名称中的特殊字符不能用于文件名
+        let name: String = rule_name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        let extension = match record.format {
+            RecordFormat::Jsonl => "jsonl",
+            RecordFormat::Har => "har",
+        };
+        Self {
+            mode: record.mode,
+            format: record.format,
+            match_body: record.match_body,
+            path: PathBuf::from(&record.dir).join(format!("{}.{}", name, extension)),
+            rule_name: rule_name.to_string(),
+            write_lock: tokio::sync::Mutex::new(()),
+            cache: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn replaying(&self) -> bool {
+        self.mode == RecordMode::Replay
+    }
+
+    pub(crate) fn recording(&self) -> bool {
+        self.mode == RecordMode::Record
+    }
+
+    /// 在后台将请求及响应写入录制文件
+    ///
+    /// 消息体为解码后的内容，超过最大长度时为 None，size 为实际长度
+    pub(crate) fn save(
+        self: &Arc<Self>,
+        request: &RouteRequest,
+        request_body: (Option<Cow<[u8]>>, usize),
+        response_head: &[u8],
+        response_body: (Option<Cow<[u8]>>, usize),
+        started: SystemTime,
+        elapsed: Duration,
+    ) {
+        let entry = match new_entry(
+            request,
+            (request_body.0.as_deref(), request_body.1),
+            response_head,
+            (response_body.0.as_deref(), response_body.1),
+            started,
+            elapsed,
+        ) {
+            Some(entry) => entry,
+            None => {
+                warn!("[{}] Invalid response not recorded", self.rule_name);
+                return;
+            }
+        };
+        let recorder = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = recorder.write(entry).await {
+                warn!(
+                    "[{}] Write {} failed: {}",
+                    recorder.rule_name,
+                    recorder.path.display(),
+                    e
+                );
+            }
+        });
+    }
+
+    async fn write(&self, entry: Entry) -> Result<()> {
+        let _lock = self.write_lock.lock().await;
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        match self.format {
+            RecordFormat::Jsonl => {
+                let mut line = serde_json::to_vec(&entry)?;
+                line.push(b'\n');
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .await?;
+                file.write_all(&line).await?;
+            }
+            RecordFormat::Har => {
+                // HAR 是一个完整的 JSON 文档，紧凑格式且已有 entry 时以 "}]}}" 结尾，只覆盖结尾追加
+                let mut file = tokio::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&self.path)
+                    .await?;
+                let mut tail = [0u8; 4];
+                if file.metadata().await?.len() >= tail.len() as u64 {
+                    file.seek(SeekFrom::End(-4)).await?;
+                    file.read_exact(&mut tail).await?;
+                }
+                if tail == *b"}]}}" {
+                    let mut data = b",".to_vec();
+                    data.extend_from_slice(&serde_json::to_vec(&entry)?);
+                    data.extend_from_slice(b"]}}");
+                    file.seek(SeekFrom::End(-3)).await?;
+                    file.write_all(&data).await?;
+                    return Ok(());
+                }
+                // 新文件或其他工具生成的文件，重写整个文件
+                drop(file);
+                let data = tokio::fs::read(&self.path).await?;
+                let mut har = if data.is_empty() {
+                    Har {
+                        log: Log {
+                            version: "1.2".to_string(),
+                            creator: NameValue {
+                                name: env!("CARGO_PKG_NAME").to_string(),
+                                value: env!("CARGO_PKG_VERSION").to_string(),
+                            },
+                            entries: Vec::new(),
+                        },
+                    }
+                } else {
+                    serde_json::from_slice(&data)?
+                };
+                har.log.entries.push(entry);
+                let tmp_path = self.path.with_extension("har.tmp");
+                tokio::fs::write(&tmp_path, serde_json::to_vec(&har)?).await?;
+                tokio::fs::rename(&tmp_path, &self.path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 读取录制文件，文件未修改时使用缓存
+    async fn entries(&self) -> Result<Arc<Vec<Entry>>> {
+        let modified = tokio::fs::metadata(&self.path).await?.modified()?;
+        if let Some((time, entries)) = self.cache.lock().unwrap().as_ref()
+            && *time == modified
+        {
+            return Ok(entries.clone());
+        }
+        let data = tokio::fs::read(&self.path).await?;
+        let entries = match self.format {
+            RecordFormat::Jsonl => data
+                .split(|b| *b == b'\n')
+                .filter(|line| !line.trim_ascii().is_empty())
+                .map(serde_json::from_slice)
+                .collect::<Result<Vec<Entry>, _>>()?,
+            RecordFormat::Har => serde_json::from_slice::<Har>(&data)?.log.entries,
+        };
+        let entries = Arc::new(entries);
+        *self.cache.lock().unwrap() = Some((modified, entries.clone()));
+        Ok(entries)
+    }
+
+    /// 按请求方法、路径、查询参数及可选的消息体哈希查找录制的响应，多个匹配时使用最后录制的
+    ///
+    /// 没有匹配的录制时返回 404，录制的响应消息体过大没有保存时返回 502
+    pub(crate) async fn replay(
+        &self,
+        request: &RouteRequest<'_>,
+        body: &[u8],
+        keep_alive: bool,
+    ) -> Result<Vec<u8>> {
+        let entries = self
+            .entries()
+            .await
+            .map_err(|e| anyhow!("Read {} failed: {}", self.path.display(), e))?;
+        let (path, query) = split_query(request.path);
+        let query = query_pairs(query);
+        let body_hash = hash(body);
+        let entry = entries.iter().rev().find(|entry| {
+            let (entry_path, _) = split_query(url_path(&entry.request.url));
+            entry.request.method.eq_ignore_ascii_case(request.method)
+                && entry_path == path
+                && sorted(&entry.request.query_string) == query
+                && (!self.match_body || entry.request.body_hash == body_hash)
+        });
+        let Some(entry) = entry else {
+            debug!(
+                "[{}] No recorded response for {} {}",
+                self.rule_name, request.method, request.path
+            );
+            let body = format!(
+                "No recorded response for {} {}",
+                request.method, request.path
+            );
+            return Ok(build_response(
+                404,
+                &[],
+                body.as_bytes(),
+                keep_alive,
+                request.method,
+            ));
+        };
+        let response = &entry.response;
+        if response.content.text.is_none() && response.content.size > 0 {
+            // 超过最大长度的消息体没有录制，不能回放空的消息体
+            let body = format!(
+                "Recorded body not captured for {} {}",
+                request.method, request.path
+            );
+            return Ok(build_response(
+                502,
+                &[],
+                body.as_bytes(),
+                keep_alive,
+                request.method,
+            ));
+        }
+        let headers: Vec<(&str, &str)> = response
+            .headers
+            .iter()
+            .map(|h| (h.name.as_str(), h.value.as_str()))
+            .collect();
+        Ok(build_response(
+            response.status,
+            &headers,
+            &response.content.data(),
+            keep_alive,
+            request.method,
+        ))
+    }
+}
+
+fn new_entry(
+    request: &RouteRequest,
+    (request_body, request_size): (Option<&[u8]>, usize),
+    response_head: &[u8],
+    (response_body, response_size): (Option<&[u8]>, usize),
+    started: SystemTime,
+    elapsed: Duration,
+) -> Option<Entry> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    res.parse(response_head).ok()?;
+    let status = res.code?;
+    let status_text = res.reason.unwrap_or_default().to_string();
+    let response_headers: Vec<NameValue> = res
+        .headers
+        .iter()
+        .map(|h| NameValue {
+            name: h.name.to_string(),
+            value: String::from_utf8_lossy(h.value).to_string(),
+        })
+        .collect();
+    let header = |headers: &[NameValue], name: &str| {
+        headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map_or(String::new(), |h| h.value.clone())
+    };
+    let request_headers: Vec<NameValue> = request
+        .headers
+        .iter()
+        .map(|(name, value)| NameValue {
+            name: name.clone(),
+            value: value.clone(),
+        })
+        .collect();
+    let (_, query) = split_query(request.path);
+    let query_string = query_pairs(query)
+        .into_iter()
+        .map(|(name, value)| NameValue { name, value })
+        .collect();
+    let post_data = (request_size > 0).then(|| {
+        Content::new(
+            request_body,
+            request_size,
+            &header(&request_headers, "content-type"),
+        )
+    });
+    let time = elapsed.as_millis() as u64;
+    Some(Entry {
+        started_date_time: chrono::DateTime::<chrono::Utc>::from(started)
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        time,
+        request: Request {
+            method: request.method.to_string(),
+            url: format!("http://{}{}", request.host, request.path),
+            http_version: "HTTP/1.1".to_string(),
+            query_string,
+            post_data,
+            headers_size: -1,
+            body_size: request_size as i64,
+            body_hash: hash(request_body.unwrap_or_default()),
+            headers: request_headers,
+        },
+        response: Response {
+            status,
+            status_text,
+            http_version: "HTTP/1.1".to_string(),
+            content: Content::new(
+                response_body,
+                response_size,
+                &header(&response_headers, "content-type"),
+            ),
+            redirect_url: header(&response_headers, "location"),
+            headers_size: -1,
+            body_size: response_size as i64,
+            headers: response_headers,
+        },
+        cache: BTreeMap::new(),
+        timings: Timings {
+            send: 0,
+            wait: time as i64,
+            receive: 0,
+        },
+    })
+}
+
+/// 回放的响应，消息体长度按录制的内容重新计算
+fn build_response(
+    status: u16,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    keep_alive: bool,
+    method: &str,
+) -> Vec<u8> {
+    let mut data = format!("HTTP/1.1 {} {}\r\n", status, reason(status)).into_bytes();
+    for (name, value) in headers {
+        let skip = [
+            "content-length",
+            "transfer-encoding",
+            "connection",
+            "keep-alive",
+        ];
+        if !skip.iter().any(|s| name.eq_ignore_ascii_case(s)) {
+            data.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+    }
+    if !matches!(status, 204 | 304) {
+        data.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    }
+    if !keep_alive {
+        data.extend_from_slice(b"Connection: close\r\n");
+    }
+    data.extend_from_slice(b"\r\n");
+    if !method.eq_ignore_ascii_case("HEAD") && !matches!(status, 204 | 304) {
+        data.extend_from_slice(body);
+    }
+    data
+}
+
+/// 解码分块传输的消息体，格式错误时返回 None
+pub(crate) fn decode_chunked(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = memchr::memmem::find(data, b"\r\n")?;
+        let line = std::str::from_utf8(&data[..line_end]).ok()?;
+        let size = usize::from_str_radix(line.split(';').next()?.trim(), 16).ok()?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(data.get(..size)?);
+        data = data.get(size + 2..)?;
+    }
+}
+
+fn hash(data: &[u8]) -> String {
+    format!("{:016x}", fnv1a(data))
+}
+
+fn url_path(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.find('/').map_or("/", |i| &rest[i..])
+}
+
+fn split_query(path: &str) -> (&str, &str) {
+    let path = path.split('#').next().unwrap_or_default();
+    path.split_once('?').unwrap_or((path, ""))
+}
+
+/// 排序后的查询参数，忽略参数顺序
+fn query_pairs(query: &str) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (name.to_string(), value.to_string())
+        })
+        .collect();
+    pairs.sort();
+    pairs
+}
+
+fn sorted(pairs: &[NameValue]) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = pairs
+        .iter()
+        .map(|p| (p.name.clone(), p.value.clone()))
+        .collect();
+    pairs.sort();
+    pairs
+}
+
+#[tokio::test]
+async fn test_record_replay() {
+    use std::time::UNIX_EPOCH;
+    let dir = std::env::temp_dir().join(format!("proxy-forward-record-{}", std::process::id()));
+    for format in [RecordFormat::Jsonl, RecordFormat::Har] {
+        let mut record = config::Record {
+            mode: RecordMode::Record,
+            dir: dir.to_string_lossy().to_string(),
+            format,
+            match_body: true,
+        };
+        let recorder = Arc::new(Recorder::new(&record, "api.test/v1"));
+        let headers = [("Content-Type".to_string(), "application/json".to_string())];
+        let request = RouteRequest {
+            method: "POST",
+            host: "api.test",
+            path: "/v1/users?b=2&a=1",
+            headers: &headers,
+        };
+        let response_head = b"HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let response_body = decode_chunked(b"2\r\nok\r\n0\r\n\r\n").unwrap();
+        let entry = new_entry(
+            &request,
+            (Some(b"{}"), 2),
+            response_head,
+            (Some(&response_body), 2),
+            UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            Duration::from_millis(5),
+        )
+        .unwrap();
+        assert_eq!(entry.started_date_time, "2023-11-14T22:13:20.123Z");
+        recorder.write(entry.clone()).await.unwrap();
+        recorder.write(entry).await.unwrap();
+
+        record.mode = RecordMode::Replay;
+        let replayer = Recorder::new(&record, "api.test/v1");
+        assert!(replayer.path.ends_with(match format {
+            RecordFormat::Jsonl => "api.test_v1.jsonl",
+            RecordFormat::Har => "api.test_v1.har",
+        }));
+        // 查询参数顺序不同也能匹配
+        let request = RouteRequest {
+            path: "/v1/users?a=1&b=2",
+            ..request
+        };
+        let response = replayer.replay(&request, b"{}", true).await.unwrap();
+        assert_eq!(
+            response,
+            b"HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nok"
+        );
+        let response = replayer.replay(&request, b"{ }", true).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+        assert_eq!(replayer.entries().await.unwrap().len(), 2);
+
+        // 没有录制的消息体不回放
+        let request = RouteRequest {
+            method: "GET",
+            path: "/v1/large",
+            ..request
+        };
+        let size = MAX_RECORD_BODY + 1;
+        let started = UNIX_EPOCH;
+        let entry = new_entry(
+            &request,
+            (None, 0),
+            response_head,
+            (None, size),
+            started,
+            Duration::ZERO,
+        );
+        recorder.write(entry.unwrap()).await.unwrap();
+        let response = replayer.replay(&request, b"", true).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 502 Bad Gateway\r\n"));
+        assert_eq!(replayer.entries().await.unwrap().len(), 3);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
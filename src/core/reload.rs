@@ -0,0 +1,185 @@
+use crate::core::config::{AppConfig, CONFIG_FILE, Rule};
+use crate::core::route::{RouteEngine, RouteRule};
+use anyhow::{Result, anyhow};
+use notify::{RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{Mutex, mpsc};
+use tracing::{error, info, warn};
+
+/// 文件变化后等待写入完成的时间，期间的多次变化只重新加载一次
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// 监听配置文件变化及 SIGHUP 信号（仅 Unix），重新加载路由规则
+///
+/// 新配置校验失败时保留原有规则。规则只影响之后解析的请求，进行中的请求继续使用已匹配的规则
+pub(crate) async fn watch(
+    route_engine: Arc<RouteEngine>,
+    config: Arc<Mutex<AppConfig>>,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    // 编辑器保存时可能先删除再创建文件，因此监听所在目录
+    let path = Path::new(CONFIG_FILE);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => std::env::current_dir()?,
+    };
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow!("Invalid config file path"))?
+        .to_os_string();
+    let watcher_tx = tx.clone();
+    let watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) if !event.kind.is_access() => {
+                if event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == Some(file_name.as_os_str()))
+                {
+                    let _ = watcher_tx.send(());
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("Watch config file error: {}", e),
+        });
+    // 文件监听不可用时仍可通过 SIGHUP 重新加载
+    let _watcher =
+        match watcher.and_then(|mut w| w.watch(&dir, RecursiveMode::NonRecursive).map(|_| w)) {
+            Ok(w) => Some(w),
+            Err(e) => {
+                warn!("Watch config file failed, reload on SIGHUP only: {}", e);
+                None
+            }
+        };
+    #[cfg(unix)]
+    {
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading {}", CONFIG_FILE);
+                if tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    while rx.recv().await.is_some() {
+        tokio::time::sleep(DEBOUNCE).await;
+        while rx.try_recv().is_ok() {}
+        let mut current = config.lock().await;
+        if let Err(e) = reload(&route_engine, &mut current).await {
+            error!("Reload {} failed, keep current rules: {:#}", CONFIG_FILE, e);
+        }
+    }
+    Ok(())
+}
+
+/// 重新读取并校验配置，规则有变化时替换
+async fn reload(route_engine: &RouteEngine, current: &mut AppConfig) -> Result<()> {
+    let config = AppConfig::load(CONFIG_FILE)?;
+    let mut rules = Vec::with_capacity(config.rules.len());
+    for (i, r) in config.rules.iter().enumerate() {
+        let rule = RouteRule::try_from(r).map_err(|e| anyhow!("rule #{}: {:#}", i, e))?;
+        rules.push(rule);
+    }
+    if config.listen_addr != current.listen_addr
+        || config.users != current.users
+        || config.admin != current.admin
+    {
+        warn!("listen_addr, users and admin changes take effect after restart");
+    }
+    let changes = diff(&current.rules, &config.rules);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    route_engine.update_rules(rules).await;
+    info!("Reloaded {} rules from {}", config.rules.len(), CONFIG_FILE);
+    for change in changes {
+        info!("{}", change);
+    }
+    current.rules = config.rules;
+    Ok(())
+}
+
+/// 对比新旧规则，名称相同的规则视为同一规则，未命名的规则按匹配条件识别
+fn diff(old: &[Rule], new: &[Rule]) -> Vec<String> {
+    let same_match = |a: &Rule, b: &Rule| {
+        if !a.name.is_empty() && !b.name.is_empty() {
+            return a.name == b.name;
+        }
+        a.matcher == b.matcher
+            && a.methods == b.methods
+            && a.headers == b.headers
+            && a.query == b.query
+            && a.cookies == b.cookies
+    };
+    let mut changes = Vec::new();
+    for rule in old {
+        if !new.iter().any(|r| same_match(r, rule)) {
+            changes.push(format!("- removed {}", label(rule)));
+        }
+    }
+    for rule in new {
+        match old.iter().find(|r| same_match(r, rule)) {
+            None => changes.push(format!("+ added {}", label(rule))),
+            Some(r) if r != rule => {
+                changes.push(format!("~ changed {} (was {})", label(rule), label(r)))
+            }
+            Some(_) => {}
+        }
+    }
+    // 只调整了顺序时也需要替换
+    if changes.is_empty() && old != new {
+        changes.push("~ rules reordered".to_string());
+    }
+    changes
+}
+
+fn label(rule: &Rule) -> String {
+    let label = format!(
+        "{}{} -> {}{}",
+        rule.matcher.addr, rule.matcher.path_prefix, rule.forward.addr, rule.forward.path_prefix
+    );
+    match rule.name.as_str() {
+        "" => label,
+        name => format!("{} ({})", name, label),
+    }
+}
+
+#[test]
+fn test_diff() {
+    let rule = |addr: &str, forward: &str| {
+        let mut rule = Rule::default();
+        rule.matcher.addr = addr.to_string();
+        rule.forward.addr = forward.to_string();
+        rule
+    };
+    let old = vec![rule("a", "1"), rule("b", "2"), rule("c", "3")];
+    let new = vec![rule("b", "2"), rule("c", "4"), rule("d", "5")];
+    assert_eq!(
+        diff(&old, &new),
+        vec![
+            "- removed a -> 1",
+            "~ changed c -> 4 (was c -> 3)",
+            "+ added d -> 5"
+        ]
+    );
+    assert!(diff(&old, &old).is_empty());
+    let reordered = vec![rule("b", "2"), rule("a", "1"), rule("c", "3")];
+    assert_eq!(diff(&old, &reordered), vec!["~ rules reordered"]);
+
+    // 命名的规则按名称识别
+    let mut renamed = rule("x", "1");
+    renamed.name = "a".to_string();
+    let mut named = rule("a", "1");
+    named.name = "a".to_string();
+    assert_eq!(
+        diff(&[named], &[renamed]),
+        vec!["~ changed a (x -> 1) (was a (a -> 1))"]
+    );
+}
@@ -0,0 +1,381 @@
+use crate::core::config;
+use crate::core::route::{RouteRule, split_host_port};
+use anyhow::{Context, anyhow};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use regex::Regex;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+/// 改写内容时缓存的响应最大长度
+pub(crate) const MAX_REWRITE_BODY: usize = 8 * 1024 * 1024;
+
+/// 响应改写
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ResponseRewrite {
+    /// 反向映射 Location 及 Set-Cookie
+    reverse_rewrite: bool,
+    /// (原状态码，None 匹配所有状态码；新状态码)
+    status: Vec<(Option<u16>, u16)>,
+    /// 内容替换
+    body: Vec<Substitution>,
+}
+
+#[derive(Debug, Clone)]
+enum Substitution {
+    Text(String, String),
+    Regex(Regex, String),
+}
+
+impl TryFrom<&config::ResponseRewrite> for ResponseRewrite {
+    type Error = anyhow::Error;
+
+    fn try_from(rewrite: &config::ResponseRewrite) -> anyhow::Result<Self> {
+        let mut status = Vec::new();
+        for (from, to) in &rewrite.status {
+            // 改为没有消息体的状态码会破坏消息边界
+            if !(200..600).contains(to) || *to == 204 || *to == 304 {
+                return Err(anyhow!("Invalid response status: {}", to));
+            }
+            let from = match from.as_str() {
+                "*" => None,
+                code => Some(
+                    code.parse()
+                        .with_context(|| format!("Invalid status: {}", code))?,
+                ),
+            };
+            status.push((from, *to));
+        }
+        // 精确的状态码优先于 *
+        status.sort_by_key(|(from, _)| from.is_none());
+        let body = rewrite
+            .body
+            .iter()
+            .map(|s| match (&s.find, &s.regex) {
+                (Some(find), None) if !find.is_empty() => {
+                    Ok(Substitution::Text(find.clone(), s.replace.clone()))
+                }
+                (None, Some(regex)) => {
+                    let regex = Regex::new(regex)
+                        .with_context(|| format!("Invalid body regex: {}", regex))?;
+                    Ok(Substitution::Regex(regex, s.replace.clone()))
+                }
+                _ => Err(anyhow!(
+                    "Body substitution requires exactly one of find or regex"
+                )),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            reverse_rewrite: rewrite.reverse_rewrite,
+            status,
+            body,
+        })
+    }
+}
+
+impl ResponseRewrite {
+    pub(crate) fn is_empty(&self) -> bool {
+        !self.reverse_rewrite && self.status.is_empty() && self.body.is_empty()
+    }
+
+    /// 替换后的状态码
+    pub(crate) fn status_for(&self, status: u16) -> Option<u16> {
+        self.status
+            .iter()
+            .find(|(from, _)| from.is_none_or(|from| from == status))
+            .map(|(_, to)| *to)
+    }
+
+    /// 是否需要改写消息体，只处理未压缩或 gzip 压缩的文本内容
+    pub(crate) fn rewrites_body(&self, head: &[u8]) -> bool {
+        !self.body.is_empty()
+            && head_value(head, "content-type").is_some_and(is_text)
+            && head_value(head, "content-encoding").is_none_or(|e| {
+                e.eq_ignore_ascii_case("identity") || e.eq_ignore_ascii_case("gzip")
+            })
+    }
+
+    /// 改写状态行、Location 及 Set-Cookie，其余消息头保持不变
+    ///
+    /// host 为客户端请求的 Host，addr 为连接的上游地址
+    pub(crate) fn rewrite_head(
+        &self,
+        head: &[u8],
+        rule: &RouteRule,
+        host: &str,
+        addr: &str,
+    ) -> Vec<u8> {
+        let mut lines = head
+            .strip_suffix(b"\r\n\r\n")
+            .unwrap_or(head)
+            .split(|b| *b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+        let status_line = lines.next().unwrap_or_default();
+        let mut data = Vec::with_capacity(head.len() + 64);
+        match self.rewrite_status_line(status_line) {
+            Some(line) => data.extend_from_slice(line.as_bytes()),
+            None => data.extend_from_slice(status_line),
+        }
+        data.extend_from_slice(b"\r\n");
+        for line in lines {
+            let rewritten = std::str::from_utf8(line)
+                .ok()
+                .filter(|_| self.reverse_rewrite)
+                .and_then(|line| line.split_once(':'))
+                .and_then(|(name, value)| {
+                    let value = value.trim();
+                    let value = if name.eq_ignore_ascii_case("location") {
+                        reverse_location(value, rule, host, addr)?
+                    } else if name.eq_ignore_ascii_case("set-cookie") {
+                        reverse_cookie(value, rule, host, addr)?
+                    } else {
+                        return None;
+                    };
+                    Some(format!("{}: {}", name, value))
+                });
+            match rewritten {
+                Some(line) => data.extend_from_slice(line.as_bytes()),
+                None => data.extend_from_slice(line),
+            }
+            data.extend_from_slice(b"\r\n");
+        }
+        data.extend_from_slice(b"\r\n");
+        data
+    }
+
+    fn rewrite_status_line(&self, line: &[u8]) -> Option<String> {
+        let line = std::str::from_utf8(line).ok()?;
+        let mut parts = line.splitn(3, ' ');
+        let version = parts.next()?;
+        let status = parts.next()?.parse().ok().filter(|s| *s >= 200)?;
+        let to = self.status_for(status)?;
+        Some(format!(
+            "{} {} {}",
+            version,
+            to,
+            crate::core::http::reason(to)
+        ))
+    }
+
+    /// 替换消息体内容，gzip 压缩的内容解压后替换再压缩，无法处理或没有替换时返回原内容
+    pub(crate) fn rewrite_body(&self, head: &[u8], body: Vec<u8>) -> Vec<u8> {
+        let gzip =
+            head_value(head, "content-encoding").is_some_and(|e| e.eq_ignore_ascii_case("gzip"));
+        if !gzip {
+            return match String::from_utf8(body) {
+                Ok(text) => self.substitute(&text).unwrap_or(text).into_bytes(),
+                Err(e) => e.into_bytes(),
+            };
+        }
+        let mut text = Vec::new();
+        let decoder = GzDecoder::new(body.as_slice());
+        match decoder
+            .take(MAX_REWRITE_BODY as u64 + 1)
+            .read_to_end(&mut text)
+        {
+            Ok(n) if n <= MAX_REWRITE_BODY => {}
+            _ => return body,
+        }
+        String::from_utf8(text)
+            .ok()
+            .and_then(|text| self.substitute(&text))
+            .and_then(|text| gzip_encode(text.as_bytes()).ok())
+            .unwrap_or(body)
+    }
+
+    /// 按顺序替换内容，没有替换时返回 None
+    fn substitute(&self, text: &str) -> Option<String> {
+        let mut text = Cow::Borrowed(text);
+        for substitution in &self.body {
+            let replaced = match substitution {
+                Substitution::Text(find, replace) if text.contains(find.as_str()) => {
+                    text.replace(find.as_str(), replace)
+                }
+                Substitution::Text(..) => continue,
+                Substitution::Regex(regex, replace) => {
+                    match regex.replace_all(&text, replace.as_str()) {
+                        Cow::Owned(replaced) => replaced,
+                        Cow::Borrowed(_) => continue,
+                    }
+                }
+            };
+            text = Cow::Owned(replaced);
+        }
+        match text {
+            Cow::Owned(text) => Some(text),
+            Cow::Borrowed(_) => None,
+        }
+    }
+}
+
+fn gzip_encode(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// 文本类型的内容
+fn is_text(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("json")
+        || mime.ends_with("javascript")
+        || mime.ends_with("xml")
+        || mime == "application/x-www-form-urlencoded"
+}
+
+fn head_value<'a>(head: &'a [u8], name: &str) -> Option<&'a str> {
+    head.split(|b| *b == b'\n')
+        .skip(1)
+        .filter_map(|line| std::str::from_utf8(line).ok()?.split_once(':'))
+        .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+/// 上游返回的 Location，指向转发地址时改为客户端请求的地址
+///
+/// `http://127.0.0.1:8080/v2/login` -> `http://example.com/api/login`，`/v2/login` -> `/api/login`
+fn reverse_location(location: &str, rule: &RouteRule, host: &str, addr: &str) -> Option<String> {
+    if let Some((scheme, rest)) = location.split_once("://") {
+        let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(end);
+        if !is_forward_host(authority, rule, addr) {
+            return None;
+        }
+        let path = match path.starts_with('/') {
+            true => rule.reverse_path(path).unwrap_or(path.to_string()),
+            false => path.to_string(),
+        };
+        return Some(format!("{}://{}{}", scheme, host, path));
+    }
+    if location.starts_with('/') && !location.starts_with("//") {
+        return rule.reverse_path(location);
+    }
+    None
+}
+
+/// 上游返回的 Set-Cookie，Path 按路径前缀反向映射，Domain 为转发地址时改为客户端请求的域名
+fn reverse_cookie(cookie: &str, rule: &RouteRule, host: &str, addr: &str) -> Option<String> {
+    let mut changed = false;
+    let attributes: Vec<String> = cookie
+        .split(';')
+        .map(|attribute| {
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let (name, value) = (name.trim(), value.trim());
+            let value = if name.eq_ignore_ascii_case("path") {
+                rule.reverse_path(value)
+                    .map(|path| match path.strip_suffix('/') {
+                        Some(path) if !path.is_empty() => path.to_string(),
+                        _ => path,
+                    })
+            } else if name.eq_ignore_ascii_case("domain")
+                && is_forward_host(value.trim_start_matches('.'), rule, addr)
+            {
+                Some(split_host_port(host).0.to_string())
+            } else {
+                None
+            };
+            match value {
+                Some(value) => {
+                    changed = true;
+                    format!("{}={}", name, value)
+                }
+                None => attribute.trim().to_string(),
+            }
+        })
+        .collect();
+    changed.then(|| attributes.join("; "))
+}
+
+/// 是否为转发地址，未指定端口时只比较主机名
+fn is_forward_host(authority: &str, rule: &RouteRule, addr: &str) -> bool {
+    let (host, port) = split_host_port(authority);
+    [addr, rule.forward.host.as_str()].iter().any(|forward| {
+        let (forward_host, forward_port) = split_host_port(forward);
+        host.eq_ignore_ascii_case(forward_host) && port.is_none_or(|p| Some(p) == forward_port)
+    })
+}
+
+#[test]
+fn test_rewrite_head() {
+    let rule = RouteRule::new("example.com", "/api", "127.0.0.1:8080", "/v2");
+    let rewrite = ResponseRewrite::try_from(&config::ResponseRewrite {
+        reverse_rewrite: true,
+        status: [("404".to_string(), 200), ("*".to_string(), 502)].into(),
+        body: Vec::new(),
+    })
+    .unwrap();
+    assert_eq!(rewrite.status_for(404), Some(200));
+    assert_eq!(rewrite.status_for(500), Some(502));
+    let head = b"HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:8080/v2/login?next=/v2\r\n\
+        Set-Cookie: sid=1; Path=/v2; Domain=127.0.0.1; HttpOnly\r\nSet-Cookie: a=1; Path=/static\r\n\r\n";
+    let head = rewrite.rewrite_head(head, &rule, "example.com", "127.0.0.1:8080");
+    assert_eq!(
+        String::from_utf8(head).unwrap(),
+        "HTTP/1.1 502 Bad Gateway\r\nLocation: http://example.com/api/login?next=/v2\r\n\
+         Set-Cookie: sid=1; Path=/api; Domain=example.com; HttpOnly\r\nSet-Cookie: a=1; Path=/static\r\n\r\n"
+    );
+    assert_eq!(
+        reverse_location("/v2/a", &rule, "example.com", "127.0.0.1:8080").unwrap(),
+        "/api/a"
+    );
+    assert!(
+        reverse_location(
+            "https://other.com/v2",
+            &rule,
+            "example.com",
+            "127.0.0.1:8080"
+        )
+        .is_none()
+    );
+    // 转发前缀为 / 时所有路径都映射到匹配前缀下
+    let rule = RouteRule::new("example.com", "/api", "127.0.0.1:8080", "/");
+    assert_eq!(
+        reverse_location("/login", &rule, "example.com", "127.0.0.1:8080").unwrap(),
+        "/api/login"
+    );
+    let cookie = reverse_cookie("a=1; Path=/", &rule, "example.com", "127.0.0.1:8080");
+    assert_eq!(cookie.unwrap(), "a=1; Path=/api");
+}
+
+#[test]
+fn test_rewrite_body() {
+    let rewrite = ResponseRewrite::try_from(&config::ResponseRewrite {
+        body: vec![
+            config::Substitution {
+                find: Some("127.0.0.1:8080".to_string()),
+                replace: "example.com".to_string(),
+                ..Default::default()
+            },
+            config::Substitution {
+                regex: Some(r#""/v2/(\w+)""#.to_string()),
+                replace: r#""/api/$1""#.to_string(),
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    })
+    .unwrap();
+    let head = b"HTTP/1.1 200 OK\r\nContent-Type: application/json; charset=utf-8\r\n\r\n";
+    assert!(rewrite.rewrites_body(head));
+    assert!(!rewrite.rewrites_body(b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\r\n"));
+    let body = br#"{"url":"http://127.0.0.1:8080","next":"/v2/users"}"#.to_vec();
+    let expected = br#"{"url":"http://example.com","next":"/api/users"}"#;
+    assert_eq!(rewrite.rewrite_body(head, body.clone()), expected);
+
+    let head = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Encoding: gzip\r\n\r\n";
+    let body = rewrite.rewrite_body(head, gzip_encode(&body).unwrap());
+    let mut text = Vec::new();
+    GzDecoder::new(body.as_slice())
+        .read_to_end(&mut text)
+        .unwrap();
+    assert_eq!(text, expected);
+    // 无效的 gzip 内容保持不变
+    assert_eq!(rewrite.rewrite_body(head, b"plain".to_vec()), b"plain");
+}
@@ -1,9 +1,32 @@
+use crate::core::config::{self, Balance, ConnectFailure, HeaderRules, Rule};
+use crate::core::fault;
+use crate::core::mock::Mock;
+use crate::core::record::Recorder;
+use crate::core::response::ResponseRewrite;
+use crate::core::upstream::UpstreamPool;
+use anyhow::{Context, anyhow};
+use ipnet::IpNet;
+use regex::Regex;
+use std::cmp::Reverse;
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
 #[derive(Debug, Clone)]
 pub(crate) struct RouteRule {
+    /// 规则名称
+    pub(crate) name: String,
+    /// 规则说明
+    pub(crate) description: Option<String>,
     /// 匹配条件
     pub(crate) match_: Match,
     /// 转发信息
     pub(crate) forward: Forward,
+    /// 优先级，越大越优先，相同时更具体的规则优先
+    pub(crate) priority: i32,
+    /// 是否启用
+    pub(crate) enabled: bool,
 }
 
 impl RouteRule {
@@ -14,34 +37,467 @@ impl RouteRule {
         forward_path_prefix: &str,
     ) -> Self {
         Self {
+            name: format!("{}{}", match_host, match_path_prefix),
+            description: None,
             match_: Match {
                 host: match_host.to_string(),
                 prefix: match_path_prefix.to_string(),
+                cidr: None,
+                ports: None,
+                path_regex: None,
+                methods: Vec::new(),
+                headers: Vec::new(),
+                query: Vec::new(),
+                cookies: Vec::new(),
             },
             forward: Forward {
                 host: forward_host.to_string(),
                 prefix: forward_path_prefix.to_string(),
                 rewrite: true,
                 connect_fail_use_original_host: false,
+                rewrite_host: false,
+                request_headers: HeaderRules::default(),
+                response_headers: HeaderRules::default(),
+                on_connect_fail: ConnectFailure::default(),
+                pool: Arc::new(UpstreamPool::new(
+                    vec![forward_host.to_string()],
+                    Balance::default(),
+                    None,
+                )),
+                health_check: None,
+                mirror: None,
+                response: ResponseRewrite::default(),
+                mock: None,
+                record: None,
+                faults: config::Faults::default(),
+                mitm: None,
             },
+            priority: 0,
+            enabled: true,
         }
     }
-    fn matches(&self, host: &str, prefix: &str) -> bool {
-        (prefix.starts_with(&self.match_.prefix))
-            && (host == self.match_.host || self.match_.host == "*")
+    fn matches(&self, request: &RouteRequest) -> bool {
+        self.check(request).is_ok()
     }
+
     fn match_host(&self, host: &str) -> bool {
-        host == self.match_.host || self.match_.host == "*"
+        self.check_host(host).is_ok()
+    }
+
+    /// 检查请求是否满足所有匹配条件，返回第一个不满足的条件
+    fn check(&self, request: &RouteRequest) -> Result<(), Mismatch<'_>> {
+        let match_ = &self.match_;
+        self.check_host(request.host)?;
+        if !request.path.starts_with(&match_.prefix) {
+            return Err(Mismatch::Prefix);
+        }
+        if match_
+            .path_regex
+            .as_ref()
+            .is_some_and(|r| !r.is_match(request.path))
+        {
+            return Err(Mismatch::PathRegex);
+        }
+        if !match_.methods.is_empty()
+            && !match_
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(request.method))
+        {
+            return Err(Mismatch::Method);
+        }
+        for p in &match_.headers {
+            let values = request
+                .headers
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case(&p.name))
+                .map(|(_, v)| v.as_str());
+            if !p.test(values) {
+                return Err(Mismatch::Header(p));
+            }
+        }
+        if let Some(p) = match_
+            .query
+            .iter()
+            .find(|p| !p.test(request.query(&p.name)))
+        {
+            return Err(Mismatch::Query(p));
+        }
+        if let Some(p) = match_
+            .cookies
+            .iter()
+            .find(|p| !p.test(request.cookies(&p.name)))
+        {
+            return Err(Mismatch::Cookie(p));
+        }
+        Ok(())
+    }
+
+    fn check_host(&self, host: &str) -> Result<(), Mismatch<'_>> {
+        if !self.enabled {
+            return Err(Mismatch::Disabled);
+        }
+        let (ip, port) = split_host_port(host);
+        if host != self.match_.host && !glob_match(&self.match_.host, host) {
+            return Err(Mismatch::Host);
+        }
+        if let Some(cidr) = &self.match_.cidr
+            && !ip
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| cidr.contains(&ip.to_canonical()))
+        {
+            return Err(Mismatch::Cidr);
+        }
+        if let Some(ports) = &self.match_.ports
+            && !port.is_some_and(|p| ports.contains(&p))
+        {
+            return Err(Mismatch::Ports);
+        }
+        Ok(())
+    }
+
+    /// 规则排序依据，依次比较优先级、域名精确程度、路径前缀长度、条件数量
+    fn rank(&self) -> (i32, u8, usize, usize, usize) {
+        let match_ = &self.match_;
+        let host_class = match match_.host.as_str() {
+            "*" => 0,
+            h if h.contains(['*', '?']) => 1,
+            _ => 2,
+        };
+        let host_literal = match_
+            .host
+            .chars()
+            .filter(|c| !matches!(c, '*' | '?'))
+            .count();
+        let conditions = [
+            match_.cidr.is_some(),
+            match_.ports.is_some(),
+            match_.path_regex.is_some(),
+            !match_.methods.is_empty(),
+        ]
+        .iter()
+        .filter(|c| **c)
+        .count()
+            + match_.headers.len()
+            + match_.query.len()
+            + match_.cookies.len();
+        (
+            self.priority,
+            host_class,
+            host_literal,
+            match_.prefix.len(),
+            conditions,
+        )
+    }
+
+    /// 转发路径，路径正则匹配时用捕获组替换转发前缀中的 `$1`，否则替换路径前缀
+    pub(crate) fn forward_path(&self, path: &str) -> Option<String> {
+        if let Some(regex) = &self.match_.path_regex {
+            return match regex.replace(path, self.forward.prefix.as_str()) {
+                std::borrow::Cow::Owned(path) => Some(path),
+                std::borrow::Cow::Borrowed(_) => None,
+            };
+        }
+        let prefix = &self.match_.prefix;
+        if prefix.is_empty() || prefix.eq("/") {
+            return None;
+        }
+        let rest = path.strip_prefix(prefix.as_str())?;
+        Some(format!("{}{}", self.forward.prefix, rest))
+    }
+
+    /// 将转发地址的路径按前缀替换反向映射回客户端请求的路径，路径正则无法反向映射
+    ///
+    /// `/v2/login` -> `/api/login`
+    pub(crate) fn reverse_path(&self, path: &str) -> Option<String> {
+        if !self.forward.rewrite || self.match_.path_regex.is_some() {
+            return None;
+        }
+        let prefix = &self.match_.prefix;
+        if prefix.is_empty() || prefix.eq("/") {
+            return None;
+        }
+        let rest = path.strip_prefix(self.forward.prefix.trim_end_matches('/'))?;
+        if !(rest.is_empty() || rest.starts_with(['/', '?', '#'])) {
+            return None;
+        }
+        Some(format!("{}{}", prefix.trim_end_matches('/'), rest))
+    }
+}
+
+impl fmt::Display for RouteRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(mock) = &self.forward.mock {
+            return write!(
+                f,
+                "{} ({}{} -> mock {})",
+                self.name, self.match_.host, self.match_.prefix, mock.status
+            );
+        }
+        write!(
+            f,
+            "{} ({}{} -> {}{})",
+            self.name, self.match_.host, self.match_.prefix, self.forward.host, self.forward.prefix
+        )
+    }
+}
+
+/// 规则不匹配的原因
+#[derive(Debug)]
+pub(crate) enum Mismatch<'a> {
+    Disabled,
+    Host,
+    Cidr,
+    Ports,
+    Prefix,
+    PathRegex,
+    Method,
+    Header(&'a Predicate),
+    Query(&'a Predicate),
+    Cookie(&'a Predicate),
+}
+
+impl fmt::Display for Mismatch<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Disabled => write!(f, "rule is disabled"),
+            Mismatch::Host => write!(f, "host does not match"),
+            Mismatch::Cidr => write!(f, "host is not in cidr"),
+            Mismatch::Ports => write!(f, "port is not in range"),
+            Mismatch::Prefix => write!(f, "path prefix does not match"),
+            Mismatch::PathRegex => write!(f, "path regex does not match"),
+            Mismatch::Method => write!(f, "method is not allowed"),
+            Mismatch::Header(p) => write!(f, "header {}", p),
+            Mismatch::Query(p) => write!(f, "query {}", p),
+            Mismatch::Cookie(p) => write!(f, "cookie {}", p),
+        }
+    }
+}
+
+/// 路由匹配过程，说明每条规则匹配或不匹配的原因
+#[derive(Debug)]
+pub(crate) struct Explanation {
+    pub(crate) request: String,
+    /// 最终选中的规则序号
+    pub(crate) selected: Option<usize>,
+    /// 每条规则的匹配结果，不匹配时为原因
+    pub(crate) rules: Vec<(String, Result<(), String>)>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "route {}", self.request)?;
+        if self.rules.is_empty() {
+            return write!(f, ": no rules");
+        }
+        for (i, (rule, result)) in self.rules.iter().enumerate() {
+            write!(f, "\n  #{} {}: ", i, rule)?;
+            match result {
+                Ok(()) if self.selected == Some(i) => write!(f, "selected")?,
+                Ok(()) => write!(
+                    f,
+                    "matched, outranked by #{}",
+                    self.selected.unwrap_or_default()
+                )?,
+                Err(reason) => write!(f, "{}", reason)?,
+            }
+        }
+        if self.selected.is_none() {
+            write!(f, "\n  no rule matched, use original host")?;
+        }
+        Ok(())
+    }
+}
+
+/// 路由匹配使用的请求信息
+pub(crate) struct RouteRequest<'a> {
+    pub(crate) method: &'a str,
+    pub(crate) host: &'a str,
+    /// 请求路径，包含查询参数
+    pub(crate) path: &'a str,
+    pub(crate) headers: &'a [(String, String)],
+}
+
+impl<'a> RouteRequest<'a> {
+    /// 查询参数的值，同名参数可能有多个
+    pub(crate) fn query(&self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let query = self.path.split_once('?').map_or("", |(_, q)| q);
+        let query = query.split('#').next().unwrap_or_default();
+        query
+            .split('&')
+            .filter_map(|pair| match pair.split_once('=') {
+                Some((n, v)) => Some((n, v)),
+                None if !pair.is_empty() => Some((pair, "")),
+                None => None,
+            })
+            .filter(move |(n, _)| *n == name)
+            .map(|(_, v)| v)
+    }
+
+    /// Cookie 的值
+    fn cookies(&self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .filter(move |(n, _)| *n == name)
+            .map(|(_, v)| v.trim_matches('"'))
+    }
+}
+
+/// 请求头、查询参数或 Cookie 的匹配条件
+#[derive(Clone, Debug)]
+pub(crate) struct Predicate {
+    pub(crate) name: String,
+    pub(crate) condition: Condition,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Condition {
+    Equals(String),
+    Contains(String),
+    Regex(Regex),
+    Present,
+    Absent,
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.condition {
+            Condition::Equals(s) => write!(f, "{} does not equal {:?}", self.name, s),
+            Condition::Contains(s) => write!(f, "{} does not contain {:?}", self.name, s),
+            Condition::Regex(r) => write!(f, "{} does not match {:?}", self.name, r.as_str()),
+            Condition::Present => write!(f, "{} is absent", self.name),
+            Condition::Absent => write!(f, "{} is present", self.name),
+        }
+    }
+}
+
+impl Predicate {
+    /// 判断同名的所有值，存在一个满足条件即可
+    fn test<'a>(&self, mut values: impl Iterator<Item = &'a str>) -> bool {
+        match &self.condition {
+            Condition::Equals(s) => values.any(|v| v == s),
+            Condition::Contains(s) => values.any(|v| v.contains(s.as_str())),
+            Condition::Regex(r) => values.any(|v| r.is_match(v)),
+            Condition::Present => values.next().is_some(),
+            Condition::Absent => values.next().is_none(),
+        }
+    }
+}
+
+impl TryFrom<&config::Predicate> for Predicate {
+    type Error = anyhow::Error;
+
+    fn try_from(predicate: &config::Predicate) -> anyhow::Result<Self> {
+        let mut conditions = Vec::new();
+        if let Some(s) = &predicate.equals {
+            conditions.push(Condition::Equals(s.clone()));
+        }
+        if let Some(s) = &predicate.contains {
+            conditions.push(Condition::Contains(s.clone()));
+        }
+        if let Some(s) = &predicate.regex {
+            let regex = Regex::new(s).with_context(|| format!("Invalid regex: {}", s))?;
+            conditions.push(Condition::Regex(regex));
+        }
+        match predicate.present {
+            Some(true) => conditions.push(Condition::Present),
+            Some(false) => conditions.push(Condition::Absent),
+            None => {}
+        }
+        if conditions.len() != 1 {
+            return Err(anyhow!(
+                "Predicate {} must set exactly one of equals, contains, regex, present",
+                predicate.name
+            ));
+        }
+        Ok(Self {
+            name: predicate.name.clone(),
+            condition: conditions.remove(0),
+        })
+    }
+}
+
+fn select(rules: &[RouteRule], matches: impl Fn(&RouteRule) -> bool) -> Option<&RouteRule> {
+    rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| matches(rule))
+        .min_by_key(|(i, rule)| (Reverse(rule.rank()), *i))
+        .map(|(_, rule)| rule)
+}
+
+/// 拆分 host:port，没有端口时返回 None
+pub(crate) fn split_host_port(host: &str) -> (&str, Option<u16>) {
+    let split = match host.rfind(']') {
+        // IPv6 [::1]:8080
+        Some(i) => host[i..].rfind(':').map(|j| i + j),
+        None => host.rfind(':').filter(|i| !host[..*i].contains(':')),
+    };
+    match split.map(|i| (&host[..i], host[i + 1..].parse().ok())) {
+        Some((h, Some(port))) => (h.trim_start_matches('[').trim_end_matches(']'), Some(port)),
+        _ => (host.trim_start_matches('[').trim_end_matches(']'), None),
+    }
+}
+
+/// 通配符匹配，`*` 匹配任意个字符，`?` 匹配单个字符，忽略大小写
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t) = (pattern.as_bytes(), text.as_bytes());
+    let (mut pi, mut ti) = (0, 0);
+    // 最近一个 * 的位置及其匹配到的文本位置，失配时回溯
+    let mut star = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == b'?' || p[pi].eq_ignore_ascii_case(&t[ti])) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == b'*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == b'*')
+}
+
+/// 解析端口范围 `8000-8999` 或单个端口
+fn parse_ports(ports: &str) -> anyhow::Result<RangeInclusive<u16>> {
+    let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+    let start = start.trim().parse::<u16>()?;
+    let end = end.trim().parse::<u16>()?;
+    if start > end {
+        return Err(anyhow!("start is greater than end"));
     }
+    Ok(start..=end)
 }
 
 /// 匹配
 #[derive(Clone, Debug)]
 pub(crate) struct Match {
-    /// 匹配目标域名或IP:PORT，也可以是 * 匹配所有
+    /// 匹配目标域名或IP:PORT，支持 * 和 ? 通配符，* 匹配所有
     pub(crate) host: String,
     /// 匹配目标请求地址前缀
     pub(crate) prefix: String,
+    /// 目标IP所在范围
+    pub(crate) cidr: Option<IpNet>,
+    /// 目标端口范围
+    pub(crate) ports: Option<RangeInclusive<u16>>,
+    /// 路径正则
+    pub(crate) path_regex: Option<Regex>,
+    /// 请求方法，为空时匹配所有方法
+    pub(crate) methods: Vec<String>,
+    /// 请求头条件
+    pub(crate) headers: Vec<Predicate>,
+    /// 查询参数条件
+    pub(crate) query: Vec<Predicate>,
+    /// Cookie 条件
+    pub(crate) cookies: Vec<Predicate>,
 }
 
 /// 转发
@@ -55,9 +511,112 @@ pub struct Forward {
     pub(crate) rewrite: bool,
     /// 转发地址连接失败时使用原始地址
     pub(crate) connect_fail_use_original_host: bool,
+    /// 将 Host 请求头改为转发地址
+    pub(crate) rewrite_host: bool,
+    /// 请求头改写
+    pub(crate) request_headers: HeaderRules,
+    /// 响应头改写
+    pub(crate) response_headers: HeaderRules,
+    /// 连接失败时的处理
+    pub(crate) on_connect_fail: ConnectFailure,
+    /// 上游地址池，包含转发地址及其他上游地址
+    pub(crate) pool: Arc<UpstreamPool>,
+    /// 上游地址池的主动健康检查，规则加载到 RouteEngine 时启动
+    pub(crate) health_check: Option<config::HealthCheck>,
+    /// 流量镜像
+    pub(crate) mirror: Option<config::Mirror>,
+    /// 响应改写
+    pub(crate) response: ResponseRewrite,
+    /// 模拟响应，设置时不连接转发地址
+    pub(crate) mock: Option<Mock>,
+    /// 录制或回放
+    pub(crate) record: Option<Arc<Recorder>>,
+    /// 故障注入
+    pub(crate) faults: config::Faults,
+    /// TLS 拦截
+    pub(crate) mitm: Option<config::Mitm>,
+}
+
+impl TryFrom<&Rule> for RouteRule {
+    type Error = anyhow::Error;
+
+    fn try_from(rule: &Rule) -> anyhow::Result<Self> {
+        let mut route_rule = Self::new(
+            &rule.matcher.addr,
+            &rule.matcher.path_prefix,
+            &rule.forward.addr,
+            &rule.forward.path_prefix,
+        );
+        route_rule.forward.rewrite = rule.forward.rewrite;
+        route_rule.forward.connect_fail_use_original_host =
+            rule.forward.connect_fail_use_original_host;
+        route_rule.forward.on_connect_fail = rule.forward.on_connect_fail.clone();
+        route_rule.forward.rewrite_host = rule.forward.rewrite_host;
+        route_rule.forward.request_headers = rule.forward.request_headers.clone();
+        route_rule.forward.response_headers = rule.forward.response_headers.clone();
+        if !rule.forward.upstreams.is_empty() || rule.forward.health_check.is_some() {
+            let addrs = std::iter::once(&rule.forward.addr)
+                .chain(&rule.forward.upstreams)
+                .cloned()
+                .collect();
+            let pool = UpstreamPool::new(
+                addrs,
+                rule.forward.balance,
+                rule.forward.hash_header.clone(),
+            );
+            route_rule.forward.pool = Arc::new(pool);
+        }
+        if let Some(mirror) = &rule.forward.mirror {
+            if !(0.0..=100.0).contains(&mirror.percent) {
+                return Err(anyhow!("Invalid mirror percent: {}", mirror.percent));
+            }
+            route_rule.forward.mirror = Some(mirror.clone());
+        }
+        route_rule.forward.response = ResponseRewrite::try_from(&rule.forward.response)?;
+        if let Some(mock) = &rule.forward.mock {
+            route_rule.forward.mock = Some(Mock::try_from(mock)?);
+        }
+        let matcher = &rule.matcher;
+        if let Some(cidr) = &matcher.cidr {
+            let cidr = cidr
+                .parse()
+                .with_context(|| format!("Invalid cidr: {}", cidr))?;
+            route_rule.match_.cidr = Some(cidr);
+        }
+        if let Some(ports) = &matcher.ports {
+            let ports = parse_ports(ports).with_context(|| format!("Invalid ports: {}", ports))?;
+            route_rule.match_.ports = Some(ports);
+        }
+        if let Some(regex) = &matcher.path_regex {
+            let regex =
+                Regex::new(regex).with_context(|| format!("Invalid path_regex: {}", regex))?;
+            route_rule.match_.path_regex = Some(regex);
+        }
+        let predicates = |list: &[config::Predicate]| -> anyhow::Result<Vec<Predicate>> {
+            list.iter().map(Predicate::try_from).collect()
+        };
+        if !rule.name.is_empty() {
+            route_rule.name = rule.name.clone();
+        }
+        route_rule.description = rule.description.clone();
+        route_rule.priority = rule.priority;
+        route_rule.enabled = rule.enabled;
+        route_rule.match_.methods = rule.methods.clone();
+        route_rule.match_.headers = predicates(&rule.headers)?;
+        route_rule.match_.query = predicates(&rule.query)?;
+        route_rule.match_.cookies = predicates(&rule.cookies)?;
+        fault::validate(&rule.forward.faults)?;
+        route_rule.forward.faults = rule.forward.faults.clone();
+        route_rule.forward.mitm = rule.forward.mitm.clone();
+        if let Some(record) = &rule.forward.record {
+            let recorder = Recorder::new(record, &route_rule.name);
+            route_rule.forward.record = Some(Arc::new(recorder));
+        }
+        route_rule.forward.health_check = rule.forward.health_check.clone();
+        Ok(route_rule)
+    }
 }
 
-use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::debug;
 
@@ -66,33 +625,260 @@ pub(crate) struct RouteEngine {
 }
 
 impl RouteEngine {
-    #[allow(unused)]
-    pub(crate) async fn resolve_target(&self, host: &str, path: &str) -> Option<RouteRule> {
-        debug!("Resolving target {host}{path}");
+    /// 在所有匹配的规则中选择优先级最高、最具体的规则，相同时选择靠前的规则
+    pub(crate) async fn resolve_target(&self, request: &RouteRequest<'_>) -> Option<RouteRule> {
+        debug!(
+            "Resolving target {} {}{}",
+            request.method, request.host, request.path
+        );
         let rules = self.rules.read().await;
-        for rule in rules.iter() {
-            // 匹配IP:PORT + 路径前缀
-            if rule.matches(host, path) {
-                return Some(rule.clone());
-            }
-        }
-        None
+        // 匹配IP:PORT + 路径前缀 + 请求方法、请求头、查询参数、Cookie
+        select(&rules, |rule| rule.matches(request)).cloned()
     }
 
     pub(crate) async fn resolve_target_by_host(&self, host: &str) -> Option<RouteRule> {
         let rules = self.rules.read().await;
-        for rule in rules.iter() {
-            if rule.match_host(host) {
-                return Some(rule.clone());
-            }
+        select(&rules, |rule| rule.match_host(host)).cloned()
+    }
+
+    /// 按目标地址匹配的规则名称，用于日志，没有匹配时返回 "-"
+    pub(crate) async fn rule_name_by_host(&self, host: &str) -> String {
+        let rules = self.rules.read().await;
+        select(&rules, |rule| rule.match_host(host))
+            .map_or_else(|| "-".to_string(), |rule| rule.name.clone())
+    }
+
+    /// 匹配目标地址且启用了 TLS 拦截的规则，443 端口同时按不带端口的主机名匹配
+    pub(crate) async fn resolve_mitm(&self, address: &str) -> Option<config::Mitm> {
+        let rules = self.rules.read().await;
+        let (host, port) = split_host_port(address);
+        let matches = |rule: &RouteRule| {
+            rule.forward.mitm.is_some()
+                && (rule.match_host(address) || (port == Some(443) && rule.match_host(host)))
+        };
+        select(&rules, matches).and_then(|r| r.forward.mitm.clone())
+    }
+
+    /// 说明请求匹配到哪条规则，以及其他规则不匹配的原因
+    pub(crate) async fn explain(&self, request: &RouteRequest<'_>) -> Explanation {
+        let rules = self.rules.read().await;
+        let selected = select(&rules, |rule| rule.matches(request))
+            .and_then(|s| rules.iter().position(|r| std::ptr::eq(r, s)));
+        Explanation {
+            request: format!("{} {}{}", request.method, request.host, request.path),
+            selected,
+            rules: rules
+                .iter()
+                .map(|rule| {
+                    let label = format!("[priority {}] {}", rule.priority, rule);
+                    (label, rule.check(request).map_err(|e| e.to_string()))
+                })
+                .collect(),
         }
-        None
     }
 
-    // 动态更新规则
-    #[allow(unused)]
-    async fn update_rules(&self, new_rules: Vec<RouteRule>) {
+    /// 动态更新规则，启动新地址池的健康检查
+    ///
+    /// 地址池及健康检查设置未变化的规则沿用原来的地址池，保留健康状态
+    pub(crate) async fn update_rules(&self, mut new_rules: Vec<RouteRule>) {
         let mut rules = self.rules.write().await;
+        for rule in &mut new_rules {
+            let forward = &mut rule.forward;
+            let unchanged = rules.iter().find(|old| {
+                old.name == rule.name
+                    && old.forward.health_check == forward.health_check
+                    && old.forward.mitm == forward.mitm
+                    && old.forward.pool.same_as(&forward.pool)
+            });
+            if let Some(old) = unchanged {
+                forward.pool = old.forward.pool.clone();
+            } else if let Some(check) = &forward.health_check {
+                let mitm = forward.mitm.clone().filter(|m| m.upstream_tls);
+                forward
+                    .pool
+                    .spawn_health_check(check.clone(), mitm, rule.name.clone());
+            }
+        }
         *rules = new_rules;
     }
 }
+
+#[test]
+fn test_match_modes() {
+    let mut rule = RouteRule::new("*.dev.local", "", "127.0.0.1:8080", "");
+    assert!(rule.match_host("api-1.dev.local"));
+    assert!(rule.match_host("API-2.dev.local"));
+    assert!(!rule.match_host("dev.local"));
+    assert!(!rule.match_host("api-1.dev.local:8080"));
+
+    rule.match_.host = "10.0.*.*:80??".to_string();
+    assert!(rule.match_host("10.0.1.2:8080"));
+    assert!(!rule.match_host("10.1.1.2:8080"));
+
+    rule.match_.host = "*".to_string();
+    rule.match_.cidr = Some("10.0.0.0/8".parse().unwrap());
+    rule.match_.ports = Some(parse_ports("8000-8999").unwrap());
+    assert!(rule.match_host("10.2.3.4:8500"));
+    assert!(!rule.match_host("10.2.3.4:9000"));
+    assert!(!rule.match_host("11.2.3.4:8500"));
+    assert!(!rule.match_host("example.com:8500"));
+    rule.match_.cidr = Some("fd00::/8".parse().unwrap());
+    assert!(rule.match_host("[fd00::1]:8080"));
+    assert!(parse_ports("9000-8000").is_err());
+}
+
+#[test]
+fn test_forward_path() {
+    let mut rule = RouteRule::new("*", "", "127.0.0.1:8080", "/api/$1");
+    rule.match_.path_regex = Some(Regex::new("^/v1/(.*)").unwrap());
+    let request = |path| RouteRequest {
+        method: "GET",
+        host: "example.com",
+        path,
+        headers: &[],
+    };
+    assert!(rule.matches(&request("/v1/users?id=1")));
+    assert!(!rule.matches(&request("/v2/users")));
+    assert_eq!(
+        rule.forward_path("/v1/users?id=1").unwrap(),
+        "/api/users?id=1"
+    );
+
+    let rule = RouteRule::new("*", "/api", "127.0.0.1:8080", "/v2");
+    assert_eq!(rule.forward_path("/api/users").unwrap(), "/v2/users");
+    assert_eq!(rule.forward_path("/static"), None);
+}
+
+#[test]
+fn test_match_request_predicates() {
+    let predicate = |name: &str, f: fn(&mut config::Predicate)| {
+        let mut p = config::Predicate {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        f(&mut p);
+        Predicate::try_from(&p).unwrap()
+    };
+    let mut rule = RouteRule::new("*", "/api", "127.0.0.1:8080", "");
+    rule.match_.methods = vec!["POST".to_string()];
+    rule.match_.headers = vec![
+        predicate("x-debug", |p| p.equals = Some("me".to_string())),
+        predicate("Authorization", |p| p.present = Some(false)),
+    ];
+    rule.match_.query = vec![predicate("v", |p| p.regex = Some("^[0-9]+$".to_string()))];
+    rule.match_.cookies = vec![predicate("session", |p| {
+        p.contains = Some("dev".to_string())
+    })];
+
+    let headers = [
+        ("Host".to_string(), "example.com".to_string()),
+        ("X-Debug".to_string(), "me".to_string()),
+        ("Cookie".to_string(), "a=1; session=\"dev-42\"".to_string()),
+    ];
+    let mut request = RouteRequest {
+        method: "post",
+        host: "example.com",
+        path: "/api/orders?x&v=12",
+        headers: &headers,
+    };
+    assert!(rule.matches(&request));
+    request.method = "GET";
+    assert!(!rule.matches(&request));
+    request.method = "POST";
+    request.path = "/api/orders?v=a";
+    assert!(!rule.matches(&request));
+    request.path = "/api/orders?v=1";
+    request.headers = &headers[..2];
+    assert!(!rule.matches(&request));
+
+    let invalid = config::Predicate {
+        name: "x".to_string(),
+        equals: Some("a".to_string()),
+        present: Some(true),
+        ..Default::default()
+    };
+    assert!(Predicate::try_from(&invalid).is_err());
+}
+
+#[tokio::test]
+async fn test_resolve_most_specific() {
+    let mut rules = vec![
+        RouteRule::new("*", "", "127.0.0.1:1", ""),
+        RouteRule::new("*.dev.local", "", "127.0.0.1:2", ""),
+        RouteRule::new("api.dev.local", "/", "127.0.0.1:3", ""),
+        RouteRule::new("api.dev.local", "/api", "127.0.0.1:4", ""),
+        RouteRule::new("api.dev.local", "/api", "127.0.0.1:5", ""),
+    ];
+    rules[0].match_.methods = vec!["DELETE".to_string()];
+    let engine = RouteEngine {
+        rules: Arc::new(RwLock::new(rules)),
+    };
+    let resolve = |host: &'static str, path: &'static str| {
+        let engine = &engine;
+        async move {
+            let request = RouteRequest {
+                method: "GET",
+                host,
+                path,
+                headers: &[],
+            };
+            engine
+                .resolve_target(&request)
+                .await
+                .map(|r| r.forward.host)
+        }
+    };
+    assert_eq!(
+        resolve("api.dev.local", "/api/a").await.unwrap(),
+        "127.0.0.1:4"
+    );
+    assert_eq!(
+        resolve("api.dev.local", "/static").await.unwrap(),
+        "127.0.0.1:3"
+    );
+    assert_eq!(resolve("web.dev.local", "/").await.unwrap(), "127.0.0.1:2");
+    assert_eq!(resolve("example.com", "/").await, None);
+
+    engine.rules.write().await[1].priority = 1;
+    assert_eq!(
+        resolve("api.dev.local", "/api/a").await.unwrap(),
+        "127.0.0.1:2"
+    );
+
+    let request = RouteRequest {
+        method: "GET",
+        host: "example.com",
+        path: "/",
+        headers: &[],
+    };
+    let explanation = engine.explain(&request).await;
+    assert_eq!(explanation.selected, None);
+    assert_eq!(
+        explanation.rules[0].1,
+        Err("method is not allowed".to_string())
+    );
+    assert_eq!(
+        explanation.rules[1].1,
+        Err("host does not match".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_update_rules_keeps_pool() {
+    let engine = RouteEngine {
+        rules: Arc::new(RwLock::new(Vec::new())),
+    };
+    let rule = |forward_host| {
+        let mut rule = RouteRule::new("example.com", "", forward_host, "");
+        rule.forward.health_check = Some(config::HealthCheck::default());
+        rule
+    };
+    let pool = || async { engine.rules.read().await[0].forward.pool.clone() };
+    engine.update_rules(vec![rule("127.0.0.1:9")]).await;
+    let first = pool().await;
+    // 地址池未变化时保留原来的健康状态
+    engine.update_rules(vec![rule("127.0.0.1:9")]).await;
+    assert!(Arc::ptr_eq(&first, &pool().await));
+    engine.update_rules(vec![rule("127.0.0.1:10")]).await;
+    assert!(!Arc::ptr_eq(&first, &pool().await));
+}
@@ -1,20 +1,46 @@
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum AuthMethod {
     NoAuth,
+    /// 用户名/密码认证 RFC 1929
+    UsernamePassword,
+    /// 没有可接受的认证方法
+    NoAcceptable,
     // 其他认证方法可根据需求扩展
 }
 
 impl AuthMethod {
-    #[allow(unused)]
     fn from_u8(value: u8) -> Option<Self> {
         match value {
             0x00 => Some(AuthMethod::NoAuth),
+            0x02 => Some(AuthMethod::UsernamePassword),
+            0xFF => Some(AuthMethod::NoAcceptable),
             _ => None,
         }
     }
     fn to_u8(&self) -> u8 {
         match self {
             AuthMethod::NoAuth => 0x00,
+            AuthMethod::UsernamePassword => 0x02,
+            AuthMethod::NoAcceptable => 0xFF,
+        }
+    }
+
+    /// 根据客户端提供的认证方法列表选择认证方法
+    ///
+    /// 配置了用户时只接受用户名/密码认证，否则只接受无认证
+    fn select(methods: &[u8], auth_required: bool) -> Self {
+        let expected = if auth_required {
+            AuthMethod::UsernamePassword
+        } else {
+            AuthMethod::NoAuth
+        };
+        if methods
+            .iter()
+            .any(|&m| AuthMethod::from_u8(m).as_ref() == Some(&expected))
+        {
+            expected
+        } else {
+            AuthMethod::NoAcceptable
         }
     }
 }
@@ -22,111 +48,288 @@ impl AuthMethod {
 #[derive(Debug)]
 enum Command {
     Connect,
-    // 可根据需求支持Bind和UDP Associate
+    Bind,
+    UdpAssociate,
 }
 
 impl Command {
     fn from_u8(value: u8) -> Option<Self> {
         match value {
             0x01 => Some(Command::Connect),
+            0x02 => Some(Command::Bind),
+            0x03 => Some(Command::UdpAssociate),
             _ => None,
         }
     }
 }
+/// 请求应答码 RFC 1928
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Reply {
+    Succeeded,
+    GeneralFailure,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+}
+
+impl Reply {
+    fn to_u8(self) -> u8 {
+        match self {
+            Reply::Succeeded => 0x00,
+            Reply::GeneralFailure => 0x01,
+            Reply::NetworkUnreachable => 0x03,
+            Reply::HostUnreachable => 0x04,
+            Reply::ConnectionRefused => 0x05,
+            Reply::TtlExpired => 0x06,
+            Reply::CommandNotSupported => 0x07,
+            Reply::AddressTypeNotSupported => 0x08,
+        }
+    }
+
+    /// 根据连接错误选择应答码
+    pub(crate) fn from_io_error(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => Reply::HostUnreachable,
+            io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            io::ErrorKind::TimedOut => Reply::TtlExpired,
+            _ => Reply::GeneralFailure,
+        }
+    }
+
+    /// 根据请求解码错误选择应答码
+    fn from_frame_error(e: &FrameError) -> Self {
+        match e {
+            FrameError::UnsupportedAddressType(_) => Reply::AddressTypeNotSupported,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+use crate::core::codec::{
+    AUTH_VERSION, Address, AuthRequest, FrameError, FrameReader, Greeting, Request, SOCKS_VERSION,
+    SOCKS4_VERSION, Socks4Request,
+};
+use crate::core::config::User;
+use crate::core::fault::{self, FaultStream, Reset};
+use crate::core::http::Origin;
+use crate::core::mitm;
 use crate::core::route::RouteEngine;
 use anyhow::{Result, anyhow};
-use bytes::{BufMut, BytesMut};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{TcpListener, TcpStream, UdpSocket, lookup_host},
 };
+use tracing::{debug, info, warn};
+
+/// 连接目标服务器的超时时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// BIND 等待入站连接的超时时间
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+/// BIND 等待入站连接期间最多缓存的客户端数据
+const BIND_MAX_PENDING: usize = 64 * 1024;
+/// SOCKS4 请求通过
+const SOCKS4_GRANTED: u8 = 0x5A;
+/// SOCKS4 请求被拒绝或失败
+const SOCKS4_REJECTED: u8 = 0x5B;
 
 pub(crate) async fn handle_client(
     mut client: TcpStream,
     route_engine: Arc<RouteEngine>,
+    users: Arc<Vec<User>>,
 ) -> Result<()> {
-    // 1. 认证协商
-    let mut buf = BytesMut::with_capacity(256);
-    client.read_buf(&mut buf).await?;
-
-    if buf.is_empty() || buf[0] != 0x05 {
-        return Err(anyhow!("Unsupported SOCKS version"));
+    let mut reader = FrameReader::new();
+    if reader.peek_version(&mut client).await? == SOCKS4_VERSION {
+        return handle_socks4(client, reader, route_engine, &users).await;
     }
 
-    let methods_count = buf[1] as usize;
-    let _methods = &buf[2..2 + methods_count];
-
-    // 选择无认证方法
-    let mut response = vec![0x05, AuthMethod::NoAuth.to_u8()]; // VER, METHOD
+    // 1. 认证协商
+    let greeting: Greeting = match reader.read(&mut client).await {
+        Ok(g) => g,
+        // 没有提供任何认证方法时同样回复无可用方法
+        Err(e @ FrameError::NoMethods) => {
+            let response = [SOCKS_VERSION, AuthMethod::NoAcceptable.to_u8()];
+            client.write_all(&response).await?;
+            client.shutdown().await.unwrap_or(());
+            return Err(e.into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let method = AuthMethod::select(&greeting.methods, !users.is_empty());
+    let response = [SOCKS_VERSION, method.to_u8()]; // VER, METHOD
     client.write_all(&response).await?;
-    buf.clear();
+    match method {
+        AuthMethod::NoAuth => {}
+        AuthMethod::UsernamePassword => authenticate(&mut client, &mut reader, &users).await?,
+        AuthMethod::NoAcceptable => {
+            client.shutdown().await.unwrap_or(());
+            return Err(anyhow!("No acceptable authentication methods"));
+        }
+    }
 
     // 2. 处理请求
-    client.read_buf(&mut buf).await?;
-    if buf.is_empty() || buf[0] != 0x05 {
-        return Err(anyhow!("Unsupported SOCKS version in request"));
-    }
-    let _cmd = Command::from_u8(buf[1]).ok_or(anyhow!("Unsupported command"))?;
-    let address_type = buf[3];
-
-    let address = match address_type {
-        0x01 => {
-            // IPv4
-            let addr = &buf[4..8];
-            format!(
-                "{}.{}.{}.{}:{}",
-                addr[0],
-                addr[1],
-                addr[2],
-                addr[3],
-                u16::from_be_bytes([buf[8], buf[9]])
-            )
-        }
-        0x03 => {
-            // Domain name
-            let len = buf[4] as usize;
-            let domain = String::from_utf8_lossy(&buf[5..5 + len]).to_string();
-            let port = u16::from_be_bytes([buf[5 + len], buf[5 + len + 1]]);
-            format!("{}:{}", domain, port)
-        }
-        _ => return Err(anyhow!("Unsupported address type")),
+    let request: Request = match reader.read(&mut client).await {
+        Ok(r) => r,
+        Err(e @ (FrameError::UnexpectedEof | FrameError::Io(_))) => return Err(e.into()),
+        Err(e) => {
+            write_reply(&mut client, Reply::from_frame_error(&e), unspecified_addr()).await?;
+            return Err(e.into());
+        }
     };
+    let Some(cmd) = Command::from_u8(request.command) else {
+        write_reply(&mut client, Reply::CommandNotSupported, unspecified_addr()).await?;
+        return Err(anyhow!("Unsupported command: {:#04x}", request.command));
+    };
+    let address = request.address.to_string();
+    // 客户端在收到应答前发送的数据
+    let early_data = reader.into_remaining();
 
-    // 3. 发送成功响应
-    response.clear();
-    response.put_u8(0x05); // VER
-    response.put_u8(0x00); // SUCCESS
-    response.put_u8(0x00); // RSV
-    response.put_u8(0x01); // IPv4
-    response.put_slice(&[0, 0, 0, 0]); // IP
-    response.put_u16(0); // Port
-    client.write_all(&response).await?;
+    match cmd {
+        Command::Connect => {}
+        Command::Bind => return bind(client, &address, &early_data).await,
+        Command::UdpAssociate => {
+            // DST.ADDR为客户端发送UDP数据的地址，通常为0.0.0.0:0，不做限制
+            debug!("UDP associate requested from {}", address);
+            return crate::core::udp::associate(client, route_engine).await;
+        }
+    }
 
-    // 4. 连接目标服务器
-    let server = TcpStream::connect(address).await?;
-
-    let route_rule = match crate::core::http::parse_http_header(&client).await {
-        // 不是http请求或解析失败
-        None => None,
-        Some((host, _path)) => match route_engine.resolve_target_by_host(&host).await {
-            // 未匹配到路由规则
-            None => None,
-            Some(r) => Some(r),
-        },
+    // 3. 连接目标服务器，连接结果确定后才发送应答
+    let server = match connect_target(&address).await {
+        Ok(s) => s,
+        Err(e) => {
+            let reply = Reply::from_io_error(&e);
+            write_reply(&mut client, reply, unspecified_addr()).await?;
+            return Err(anyhow!(
+                "Connect to {} failed ({:?}): {}",
+                address,
+                reply,
+                e
+            ));
+        }
+    };
+
+    // 4. 发送成功响应，BND为实际连接目标服务器使用的本地地址
+    write_reply(&mut client, Reply::Succeeded, server.local_addr()?).await?;
+    relay(client, server, &address, &early_data, route_engine).await
+}
+
+/// 处理 SOCKS4/4a 请求，只支持 CONNECT
+async fn handle_socks4(
+    mut client: TcpStream,
+    mut reader: FrameReader,
+    route_engine: Arc<RouteEngine>,
+    users: &[User],
+) -> Result<()> {
+    let request: Socks4Request = reader.read(&mut client).await?;
+    // SOCKS4 无法进行密码认证，配置了用户时拒绝访问
+    if !users.is_empty() {
+        write_socks4_reply(&mut client, SOCKS4_REJECTED, unspecified_addr()).await?;
+        return Err(anyhow!(
+            "SOCKS4 is not allowed when authentication is required"
+        ));
+    }
+    if request.command != 0x01 {
+        write_socks4_reply(&mut client, SOCKS4_REJECTED, unspecified_addr()).await?;
+        return Err(anyhow!(
+            "Unsupported SOCKS4 command: {:#04x}",
+            request.command
+        ));
+    }
+    let address = request.address.to_string();
+    debug!(
+        "[{}] SOCKS4 connect to {} by user: {}",
+        route_engine.rule_name_by_host(&address).await,
+        address,
+        String::from_utf8_lossy(&request.user_id)
+    );
+    let early_data = reader.into_remaining();
+
+    let server = match connect_target(&address).await {
+        Ok(s) => s,
+        Err(e) => {
+            write_socks4_reply(&mut client, SOCKS4_REJECTED, unspecified_addr()).await?;
+            return Err(anyhow!("Connect to {} failed: {}", address, e));
+        }
     };
+    write_socks4_reply(&mut client, SOCKS4_GRANTED, server.local_addr()?).await?;
+    relay(client, server, &address, &early_data, route_engine).await
+}
 
-    if let None = route_rule {
+/// 连接建立后转发数据，HTTP请求匹配到路由规则时交由 http 模块处理
+pub(crate) async fn relay(
+    client: TcpStream,
+    mut server: TcpStream,
+    address: &str,
+    early_data: &[u8],
+    route_engine: Arc<RouteEngine>,
+) -> Result<()> {
+    // 握手后已读取的数据优先，否则窥探客户端的首个请求
+    let mut buf = [0u8; 4096];
+    let head = if early_data.is_empty() {
+        let n = client.peek(&mut buf).await?;
+        &buf[..n]
+    } else {
+        early_data
+    };
+    let is_http = crate::core::http::parse_http_header(head).is_some();
+    // TLS 握手且目标地址启用了拦截时解密后按 HTTP 处理
+    if !is_http
+        && head.first() == Some(&0x16)
+        && let Some(mitm) = route_engine.resolve_mitm(address).await
+    {
+        return mitm::intercept(client, server, address, early_data, mitm, route_engine).await;
+    }
+
+    if !is_http {
+        // 不是http请求或解析失败，原样转发，按目标地址匹配的规则注入故障
+        let (injection, rule_name) = match route_engine.resolve_target_by_host(address).await {
+            Some(rule) => (fault::roll(&rule.forward.faults), rule.name),
+            None => (Default::default(), "-".to_string()),
+        };
+        if injection.reset {
+            debug!(
+                "[{}] Reset connection to {} by fault injection",
+                rule_name, address
+            );
+            client.reset()?;
+            return Ok(());
+        }
+        if let Some(delay) = injection.delay {
+            tokio::time::sleep(delay).await;
+        }
+        server.write_all(early_data).await?;
+        let client = FaultStream::new(client, injection.throttle, injection.truncate);
         // 拆分客户端和服务器流为读写两半
         let (mut client_reader, mut client_writer) = tokio::io::split(client);
         let (mut server_reader, mut server_writer) = tokio::io::split(server);
         let client_to_target = tokio::io::copy(&mut client_reader, &mut server_writer);
         let target_to_client = tokio::io::copy(&mut server_reader, &mut client_writer);
-        tokio::try_join!(client_to_target, target_to_client)?;
+        match tokio::try_join!(client_to_target, target_to_client) {
+            Err(e) if fault::is_truncated(&e) => {
+                debug!(
+                    "[{}] Connection to {} truncated by fault injection",
+                    rule_name, address
+                )
+            }
+            result => {
+                result?;
+            }
+        }
         return Ok(());
     }
-    let rule = route_rule.unwrap();
-    crate::core::http::forward_handle(client, server, &rule).await?;
+    // 每个请求单独匹配路由规则
+    let client_addr = client.peer_addr()?;
+    let origin = Origin::Tunnel(server.peer_addr()?.to_string(), server);
+    crate::core::http::forward_handle(client, client_addr, early_data, origin, route_engine)
+        .await?;
     Ok(())
 
     /*
@@ -160,7 +363,179 @@ pub(crate) async fn handle_client(
     // tokio::try_join!(client_to_target, target_to_client)?;*/
 }
 
+/// 处理 BIND 请求
+///
+/// 监听端口并通过第一次应答告知客户端，等待目标服务器连入后
+/// 通过第二次应答告知连入方地址，然后开始转发数据
+async fn bind(mut client: TcpStream, address: &str, early_data: &[u8]) -> Result<()> {
+    // 使用访问目标服务器时的本地地址监听，保证目标服务器可以连入
+    let target = match lookup_host(address).await {
+        Ok(mut addrs) => addrs.next(),
+        Err(e) => {
+            write_reply(&mut client, Reply::HostUnreachable, unspecified_addr()).await?;
+            return Err(e.into());
+        }
+    };
+    let client_ip = client.local_addr()?.ip().to_canonical();
+    let local_ip = match target {
+        Some(target) => route_local_ip(target).await.unwrap_or(client_ip),
+        None => client_ip,
+    };
+    let listener = match TcpListener::bind(SocketAddr::new(local_ip, 0)).await {
+        Ok(l) => l,
+        Err(e) => {
+            write_reply(&mut client, Reply::GeneralFailure, unspecified_addr()).await?;
+            return Err(e.into());
+        }
+    };
+    let listen_addr = listener.local_addr()?;
+    write_reply(&mut client, Reply::Succeeded, listen_addr).await?;
+    info!("BIND for {} listening on {}", address, listen_addr);
+
+    // 等待期间客户端发送的数据在连入后转发给对方
+    let mut pending = early_data.to_vec();
+    let mut buf = [0u8; 4096];
+    let accept = tokio::time::timeout(BIND_ACCEPT_TIMEOUT, listener.accept());
+    tokio::pin!(accept);
+    let accepted = loop {
+        tokio::select! {
+            res = &mut accept => break res,
+            res = client.read(&mut buf), if pending.len() < BIND_MAX_PENDING => match res {
+                Ok(0) | Err(_) => return Err(anyhow!("Client closed before BIND connection")),
+                Ok(n) => pending.extend_from_slice(&buf[..n]),
+            },
+        }
+    };
+    let (mut server, peer) = match accepted {
+        Ok(Ok(conn)) => conn,
+        Ok(Err(e)) => {
+            write_reply(&mut client, Reply::GeneralFailure, listen_addr).await?;
+            return Err(e.into());
+        }
+        Err(_) => {
+            write_reply(&mut client, Reply::TtlExpired, listen_addr).await?;
+            return Err(anyhow!("BIND accept timed out"));
+        }
+    };
+    if let Some(target) = target
+        && target.ip().to_canonical() != peer.ip().to_canonical()
+    {
+        warn!("BIND connection from {} does not match {}", peer, target);
+    }
+    write_reply(&mut client, Reply::Succeeded, canonical_addr(peer)).await?;
+    debug!("BIND connection from {}", peer);
+    if !pending.is_empty() {
+        server.write_all(&pending).await?;
+    }
+
+    tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+    Ok(())
+}
+
+/// 连接目标服务器
+///
+/// 域名解析失败视为主机不可达，连接超时视为TTL过期，便于映射应答码
+pub(crate) async fn connect_target(address: &str) -> io::Result<TcpStream> {
+    let addrs = lookup_host(address)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::HostUnreachable, e))?;
+    let mut last_err = None;
+    for addr in addrs {
+        match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last_err = Some(e),
+            Err(_) => {
+                last_err = Some(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connect to {} timed out", addr),
+                ))
+            }
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::HostUnreachable, "no address resolved")))
+}
+
+/// 访问目标地址时使用的本地IP，UDP connect 不会发送数据
+async fn route_local_ip(target: SocketAddr) -> Option<IpAddr> {
+    let unspecified = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(unspecified).await.ok()?;
+    socket.connect(target).await.ok()?;
+    socket.local_addr().ok().map(|a| a.ip())
+}
+
+/// 失败应答使用的地址 0.0.0.0:0
+pub(crate) fn unspecified_addr() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+}
+
+/// 将双栈监听下的 IPv4 映射地址还原为 IPv4 地址
+pub(crate) fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// 发送请求应答
+///
+/// +----+-----+-------+------+----------+----------+
+/// |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+/// +----+-----+-------+------+----------+----------+
+pub(crate) async fn write_reply(
+    client: &mut TcpStream,
+    rep: Reply,
+    bind: SocketAddr,
+) -> Result<()> {
+    let mut response = vec![SOCKS_VERSION, rep.to_u8(), 0x00]; // VER, REP, RSV
+    Address::from(canonical_addr(bind)).encode(&mut response);
+    client.write_all(&response).await?;
+    Ok(())
+}
+
+/// 发送 SOCKS4 应答
+///
+/// +----+----+----+----+----+----+----+----+
+/// | VN | CD | DSTPORT |      DSTIP        |
+/// +----+----+----+----+----+----+----+----+
+async fn write_socks4_reply(client: &mut TcpStream, cd: u8, bind: SocketAddr) -> Result<()> {
+    let (ip, port) = match canonical_addr(bind) {
+        SocketAddr::V4(v4) => (*v4.ip(), v4.port()),
+        // SOCKS4 只能表示IPv4地址
+        SocketAddr::V6(_) => (Ipv4Addr::UNSPECIFIED, 0),
+    };
+    let mut response = vec![0x00, cd]; // VN, CD
+    response.extend_from_slice(&port.to_be_bytes());
+    response.extend_from_slice(&ip.octets());
+    client.write_all(&response).await?;
+    Ok(())
+}
+
+/// 用户名/密码认证子协商 RFC 1929
+async fn authenticate(
+    client: &mut TcpStream,
+    reader: &mut FrameReader,
+    users: &[User],
+) -> Result<()> {
+    let request: AuthRequest = reader.read(client).await?;
+    let passed = users.iter().any(|u| {
+        u.username.as_bytes() == request.username && u.password.as_bytes() == request.password
+    });
+    let username = String::from_utf8_lossy(&request.username);
+    // VER, STATUS 0x00成功，其他失败
+    let status = if passed { 0x00 } else { 0x01 };
+    client.write_all(&[AUTH_VERSION, status]).await?;
+    if !passed {
+        warn!("Authentication failed for user: {}", username);
+        client.shutdown().await.unwrap_or(());
+        return Err(anyhow!("Authentication failed"));
+    }
+    debug!("Authenticated user: {}", username);
+    Ok(())
+}
+
 #[tokio::test]
+#[ignore = "手动调试用，监听固定端口 1080 且不会退出"]
 async fn test_socks() -> Result<()> {
     use tokio::net::TcpListener;
     use tokio::sync::RwLock;
@@ -178,13 +553,128 @@ async fn test_socks() -> Result<()> {
                 "127.0.0.1:8686",
                 "",
             );
-            let mut vec = Vec::new();
-            vec.push(rule);
-            let rules = Arc::new(RwLock::new(vec));
+            let rules = Arc::new(RwLock::new(vec![rule]));
             let route_engine = Arc::new(RouteEngine { rules });
-            if let Err(e) = handle_client(socket, route_engine).await {
+            if let Err(e) = handle_client(socket, route_engine, Arc::new(Vec::new())).await {
                 error!("Error handling client: {}", e);
             }
         });
     }
 }
+
+#[tokio::test]
+async fn test_bind() -> Result<()> {
+    use tokio::net::TcpListener;
+    use tokio::sync::RwLock;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?;
+    let proxy = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        let route_engine = Arc::new(RouteEngine {
+            rules: Arc::new(RwLock::new(Vec::new())),
+        });
+        handle_client(socket, route_engine, Arc::new(Vec::new())).await
+    });
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    client.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).await?;
+    assert_eq!(reply, [0x05, 0x00]);
+    client
+        .write_all(&[0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 9])
+        .await?;
+    // 第一次应答为监听地址
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await?;
+    assert_eq!(reply[..4], [0x05, 0x00, 0x00, 0x01]);
+    let bound = SocketAddr::from((
+        [reply[4], reply[5], reply[6], reply[7]],
+        u16::from_be_bytes([reply[8], reply[9]]),
+    ));
+    // 连入前发送的数据不会丢失
+    client.write_all(b"early").await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // 第二次应答为连入的地址
+    let mut peer = TcpStream::connect(bound).await?;
+    client.read_exact(&mut reply).await?;
+    assert_eq!(reply[..4], [0x05, 0x00, 0x00, 0x01]);
+    let peer_addr = SocketAddr::from((
+        [reply[4], reply[5], reply[6], reply[7]],
+        u16::from_be_bytes([reply[8], reply[9]]),
+    ));
+    assert_eq!(peer_addr, peer.local_addr()?);
+
+    client.write_all(b"ping").await?;
+    let mut data = [0u8; 9];
+    peer.read_exact(&mut data).await?;
+    assert_eq!(&data, b"earlyping");
+    let mut data = [0u8; 4];
+    peer.write_all(b"pong").await?;
+    client.read_exact(&mut data).await?;
+    assert_eq!(&data, b"pong");
+    drop((client, peer));
+    proxy.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_no_methods() -> Result<()> {
+    use tokio::net::TcpListener;
+    use tokio::sync::RwLock;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?;
+    let proxy = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        let route_engine = Arc::new(RouteEngine {
+            rules: Arc::new(RwLock::new(Vec::new())),
+        });
+        handle_client(socket, route_engine, Arc::new(Vec::new())).await
+    });
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    client.write_all(&[0x05, 0x00]).await?;
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await?;
+    assert_eq!(response, [0x05, 0xFF]);
+    assert!(proxy.await?.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_select_auth_method() {
+    assert_eq!(AuthMethod::select(&[0x00], false), AuthMethod::NoAuth);
+    assert_eq!(
+        AuthMethod::select(&[0x00, 0x02], true),
+        AuthMethod::UsernamePassword
+    );
+    assert_eq!(AuthMethod::select(&[0x00], true), AuthMethod::NoAcceptable);
+    assert_eq!(AuthMethod::select(&[0x02], false), AuthMethod::NoAcceptable);
+    assert_eq!(AuthMethod::select(&[], false), AuthMethod::NoAcceptable);
+}
+
+#[test]
+fn test_encode_address() {
+    let encode = |addr: SocketAddr| {
+        let mut buf = Vec::new();
+        Address::from(addr).encode(&mut buf);
+        buf
+    };
+    let v4: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+    assert_eq!(encode(v4), vec![0x01, 127, 0, 0, 1, 0x04, 0x38]);
+    let v6: SocketAddr = "[::1]:1080".parse().unwrap();
+    let encoded = encode(v6);
+    assert_eq!((encoded[0], encoded.len()), (0x04, 19));
+    let mapped: SocketAddr = "[::ffff:127.0.0.1]:1080".parse().unwrap();
+    assert_eq!(canonical_addr(mapped), v4);
+}
+
+#[test]
+fn test_reply_from_io_error() {
+    let reply = |kind| Reply::from_io_error(&io::Error::from(kind));
+    assert_eq!(reply(io::ErrorKind::ConnectionRefused).to_u8(), 0x05);
+    assert_eq!(reply(io::ErrorKind::HostUnreachable).to_u8(), 0x04);
+    assert_eq!(reply(io::ErrorKind::NetworkUnreachable).to_u8(), 0x03);
+    assert_eq!(reply(io::ErrorKind::TimedOut).to_u8(), 0x06);
+    assert_eq!(reply(io::ErrorKind::Other).to_u8(), 0x01);
+}
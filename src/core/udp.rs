@@ -0,0 +1,450 @@
+use crate::core::codec::{Address, Decode};
+use crate::core::route::RouteEngine;
+use crate::core::socks::{Reply, canonical_addr, unspecified_addr, write_reply};
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UdpSocket, lookup_host};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
+
+/// UDP数据报最大长度
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// 域名目标解析结果的缓存时间
+const DNS_CACHE_TTL: Duration = Duration::from_secs(60);
+/// 缓存的域名数量上限，超过时清空
+const MAX_DNS_CACHE: usize = 1024;
+/// 每个解析中的域名最多排队的数据报数量
+const MAX_PENDING_DATAGRAMS: usize = 64;
+
+/// 处理 UDP ASSOCIATE 请求
+///
+/// 绑定中继UDP端口并在应答中告知客户端，关联的生命周期与控制TCP连接一致
+pub(crate) async fn associate(mut client: TcpStream, route_engine: Arc<RouteEngine>) -> Result<()> {
+    let peer_ip = client.peer_addr()?.ip().to_canonical();
+    // 中继端口与控制连接使用同一本地地址，保证客户端可达
+    let local_ip = client.local_addr()?.ip().to_canonical();
+    let sockets = tokio::try_join!(
+        UdpSocket::bind(SocketAddr::new(local_ip, 0)),
+        UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+    );
+    let (relay, outbound_v4) = match sockets {
+        Ok(s) => s,
+        Err(e) => {
+            write_reply(&mut client, Reply::GeneralFailure, unspecified_addr()).await?;
+            return Err(e.into());
+        }
+    };
+    // 主机未启用IPv6时只转发IPv4目标
+    let outbound_v6 = UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))
+        .await
+        .map_err(|e| debug!("Bind IPv6 UDP socket failed: {}", e))
+        .ok();
+    let outbound = Outbound {
+        v4: outbound_v4,
+        v6: outbound_v6,
+    };
+    let relay_addr = relay.local_addr()?;
+    write_reply(&mut client, Reply::Succeeded, relay_addr).await?;
+    info!("UDP relay for {} listening on {}", peer_ip, relay_addr);
+
+    // 客户端发送UDP数据的地址，收到第一个数据报后确定
+    let mut client_addr: Option<SocketAddr> = None;
+    // 被转发的实际目标地址 -> 客户端请求的原始地址，回包时还原
+    let mut origins: HashMap<SocketAddr, Address> = HashMap::new();
+    // 域名目标的解析结果及解析时间
+    let mut dns_cache: HashMap<String, (SocketAddr, Instant)> = HashMap::new();
+    let mut pending = PendingLookups::default();
+    let (resolved_tx, mut resolved_rx) = mpsc::channel(64);
+
+    let mut ctrl_buf = [0u8; 64];
+    let mut client_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut remote_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut remote_buf_v6 = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            res = client.read(&mut ctrl_buf) => {
+                // 控制连接关闭时结束关联
+                match res {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                }
+            }
+            res = relay.recv_from(&mut client_buf) => {
+                let Some((n, from)) = received(res)? else { continue };
+                let from = canonical_addr(from);
+                // 只接受控制连接所属客户端的数据报
+                if from.ip() != peer_ip {
+                    debug!("Drop UDP datagram from unknown source {}", from);
+                    continue;
+                }
+                client_addr = Some(from);
+
+                let (address, data) = match decapsulate(&client_buf[..n]) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        debug!("Drop UDP datagram from {}: {}", from, e);
+                        continue;
+                    }
+                };
+                let target = address.to_string();
+                let (dest, rule_name) = match route_engine.resolve_target_by_host(&target).await {
+                    // 模拟响应只用于 HTTP 请求
+                    Some(rule) if rule.forward.mock.is_none() => {
+                        debug!("[{}] UDP route {} -> {}", rule.name, target, rule.forward.host);
+                        (rule.forward.host, rule.name)
+                    }
+                    _ => (target.clone(), "-".to_string()),
+                };
+                let cached = cached_addr(&dns_cache, &dest);
+                let datagram = Datagram {
+                    dest,
+                    target,
+                    address,
+                    data: data.to_vec(),
+                    rule_name,
+                };
+                match cached {
+                    Some(dest_addr) => outbound.send(&mut origins, dest_addr, datagram).await,
+                    // 域名在后台解析，不阻塞其他目标的转发，同一域名只解析一次
+                    None => {
+                        if let Some(dest) = pending.queue(datagram) {
+                            let resolved_tx = resolved_tx.clone();
+                            tokio::spawn(async move {
+                                let addr = lookup_host(dest.as_str())
+                                    .await
+                                    .ok()
+                                    .and_then(|mut a| a.next());
+                                resolved_tx.send((dest, addr)).await.unwrap_or(());
+                            });
+                        }
+                    }
+                }
+            }
+            Some((dest, addr)) = resolved_rx.recv() => {
+                let datagrams = pending.take(&dest);
+                let Some(dest_addr) = addr else {
+                    let rule_name = datagrams.first().map_or("-", |d| d.rule_name.as_str());
+                    error!("[{}] Resolve UDP target {} failed", rule_name, dest);
+                    continue;
+                };
+                if dns_cache.len() >= MAX_DNS_CACHE {
+                    dns_cache.clear();
+                }
+                dns_cache.insert(dest, (dest_addr, Instant::now()));
+                for datagram in datagrams {
+                    outbound.send(&mut origins, dest_addr, datagram).await;
+                }
+            }
+            res = outbound.v4.recv_from(&mut remote_buf) => {
+                let Some((n, from)) = received(res)? else { continue };
+                if let Some(client_addr) = client_addr {
+                    let packet = encapsulate(&origins, from, &remote_buf[..n]);
+                    if let Err(e) = relay.send_to(&packet, client_addr).await {
+                        error!("UDP send to client {} error: {}", client_addr, e);
+                    }
+                }
+            }
+            res = recv_optional(outbound.v6.as_ref(), &mut remote_buf_v6) => {
+                let Some((n, from)) = received(res)? else { continue };
+                if let Some(client_addr) = client_addr {
+                    let packet = encapsulate(&origins, from, &remote_buf_v6[..n]);
+                    if let Err(e) = relay.send_to(&packet, client_addr).await {
+                        error!("UDP send to client {} error: {}", client_addr, e);
+                    }
+                }
+            }
+        }
+    }
+    debug!("UDP association for {} closed", peer_ip);
+    Ok(())
+}
+
+/// 等待发送到目标的数据报
+struct Datagram {
+    /// 实际发送的地址，匹配规则时为转发地址
+    dest: String,
+    /// 客户端请求的地址
+    target: String,
+    address: Address,
+    data: Vec<u8>,
+    rule_name: String,
+}
+
+/// 正在解析的域名及排队等待解析结果的数据报
+#[derive(Default)]
+struct PendingLookups {
+    queues: HashMap<String, Vec<Datagram>>,
+}
+
+impl PendingLookups {
+    /// 数据报加入解析队列，需要发起新的解析时返回域名
+    fn queue(&mut self, datagram: Datagram) -> Option<String> {
+        match self.queues.entry(datagram.dest.clone()) {
+            Entry::Occupied(mut entry) => {
+                let queue = entry.get_mut();
+                if queue.len() < MAX_PENDING_DATAGRAMS {
+                    queue.push(datagram);
+                } else {
+                    debug!(
+                        "[{}] Drop UDP datagram to {}: too many pending",
+                        datagram.rule_name, datagram.dest
+                    );
+                }
+                None
+            }
+            Entry::Vacant(entry) => {
+                let dest = entry.key().clone();
+                entry.insert(vec![datagram]);
+                Some(dest)
+            }
+        }
+    }
+
+    /// 解析完成，取出排队的数据报
+    fn take(&mut self, dest: &str) -> Vec<Datagram> {
+        self.queues.remove(dest).unwrap_or_default()
+    }
+}
+
+/// 出站套接字，主机未启用IPv6时没有IPv6套接字
+struct Outbound {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+}
+
+impl Outbound {
+    /// 按目标地址族选择套接字发送数据报
+    async fn send(
+        &self,
+        origins: &mut HashMap<SocketAddr, Address>,
+        dest_addr: SocketAddr,
+        datagram: Datagram,
+    ) {
+        let rule_name = datagram.rule_name;
+        if datagram.dest != datagram.target {
+            origins.insert(dest_addr, datagram.address);
+        } else {
+            origins.remove(&dest_addr);
+        }
+        let socket = match dest_addr {
+            SocketAddr::V4(_) => Some(&self.v4),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        };
+        let Some(socket) = socket else {
+            error!(
+                "[{}] IPv6 is not available for UDP target {}",
+                rule_name, dest_addr
+            );
+            return;
+        };
+        if let Err(e) = socket.send_to(&datagram.data, dest_addr).await {
+            error!("[{}] UDP send to {} error: {}", rule_name, dest_addr, e);
+        }
+    }
+}
+
+/// IP 地址直接使用，域名使用未过期的解析结果
+fn cached_addr(
+    dns_cache: &HashMap<String, (SocketAddr, Instant)>,
+    dest: &str,
+) -> Option<SocketAddr> {
+    if let Ok(addr) = dest.parse() {
+        return Some(addr);
+    }
+    dns_cache
+        .get(dest)
+        .filter(|(_, resolved)| resolved.elapsed() < DNS_CACHE_TTL)
+        .map(|(addr, _)| *addr)
+}
+
+/// 单个目标不可达等临时错误不结束关联，返回 None 时忽略本次接收
+fn received(res: io::Result<(usize, SocketAddr)>) -> io::Result<Option<(usize, SocketAddr)>> {
+    match res {
+        Ok(received) => Ok(Some(received)),
+        Err(e) if is_transient(&e) => {
+            debug!("UDP receive error ignored: {}", e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+/// 未绑定的套接字永远不会收到数据
+async fn recv_optional(
+    socket: Option<&UdpSocket>,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 封装UDP应答头，被转发的目标还原为客户端请求的原始地址
+fn encapsulate(origins: &HashMap<SocketAddr, Address>, from: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x00, 0x00, 0x00]; // RSV, FRAG
+    match origins.get(&from) {
+        Some(origin) => origin.encode(&mut packet),
+        None => Address::from(from).encode(&mut packet),
+    }
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// 解析UDP请求头
+///
+/// +----+------+------+----------+----------+----------+
+/// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+/// +----+------+------+----------+----------+----------+
+///
+/// 返回目标地址及数据
+fn decapsulate(packet: &[u8]) -> Result<(Address, &[u8])> {
+    if packet.len() < 4 {
+        return Err(anyhow!("Datagram too short"));
+    }
+    if packet[2] != 0x00 {
+        // 不支持分片，直接丢弃
+        return Err(anyhow!("Fragmented datagram is not supported"));
+    }
+    let (address, len) = Address::decode(&packet[3..])?.ok_or(anyhow!("Incomplete address"))?;
+    Ok((address, &packet[3 + len..]))
+}
+
+#[test]
+fn test_received() {
+    let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+    assert!(received(Err(refused)).unwrap().is_none());
+    let invalid = io::Error::from(io::ErrorKind::InvalidInput);
+    assert!(received(Err(invalid)).is_err());
+}
+
+#[test]
+fn test_cached_addr() {
+    let addr: SocketAddr = "10.0.0.1:53".parse().unwrap();
+    let mut dns_cache = HashMap::new();
+    assert_eq!(cached_addr(&dns_cache, "10.0.0.1:53"), Some(addr));
+    assert_eq!(cached_addr(&dns_cache, "dns.test:53"), None);
+    dns_cache.insert("dns.test:53".to_string(), (addr, Instant::now()));
+    assert_eq!(cached_addr(&dns_cache, "dns.test:53"), Some(addr));
+    let expired = Instant::now() - DNS_CACHE_TTL;
+    dns_cache.insert("dns.test:53".to_string(), (addr, expired));
+    assert_eq!(cached_addr(&dns_cache, "dns.test:53"), None);
+}
+
+#[test]
+fn test_decapsulate() {
+    let packet = [0, 0, 0, 0x01, 10, 0, 0, 1, 0, 53, b'h', b'i'];
+    let (address, data) = decapsulate(&packet).unwrap();
+    assert_eq!(
+        (address.to_string().as_str(), data),
+        ("10.0.0.1:53", b"hi".as_ref())
+    );
+    assert!(decapsulate(&[0, 0, 1, 0x01, 10, 0, 0, 1, 0, 53]).is_err());
+    assert!(decapsulate(&[0, 0, 0, 0x01, 10, 0]).is_err());
+    assert!(decapsulate(&[0, 0]).is_err());
+}
+
+#[test]
+fn test_pending_lookups() {
+    let datagram = |data: &[u8]| Datagram {
+        dest: "dns.test:53".to_string(),
+        target: "dns.test:53".to_string(),
+        address: Address::Domain("dns.test".to_string(), 53),
+        data: data.to_vec(),
+        rule_name: "-".to_string(),
+    };
+    let mut pending = PendingLookups::default();
+    assert_eq!(
+        pending.queue(datagram(b"a")).as_deref(),
+        Some("dns.test:53")
+    );
+    assert_eq!(pending.queue(datagram(b"b")), None);
+    for _ in 0..MAX_PENDING_DATAGRAMS {
+        pending.queue(datagram(b"c"));
+    }
+    let datagrams = pending.take("dns.test:53");
+    assert_eq!(datagrams.len(), MAX_PENDING_DATAGRAMS);
+    assert_eq!(
+        (&datagrams[0].data[..], &datagrams[1].data[..]),
+        (&b"a"[..], &b"b"[..])
+    );
+    assert!(pending.take("dns.test:53").is_empty());
+    assert!(pending.queue(datagram(b"d")).is_some());
+}
+
+#[tokio::test]
+async fn test_associate() -> Result<()> {
+    use crate::core::route::RouteRule;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::RwLock;
+
+    let echo = UdpSocket::bind("127.0.0.1:0").await?;
+    let echo_addr = echo.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+            echo.send_to(&buf[..n], from).await.unwrap_or(0);
+        }
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        let rule = RouteRule::new("udp.test:53", "", &echo_addr.to_string(), "");
+        let route_engine = Arc::new(RouteEngine {
+            rules: Arc::new(RwLock::new(vec![rule])),
+        });
+        crate::core::socks::handle_client(socket, route_engine, Arc::new(Vec::new())).await
+    });
+
+    let mut control = TcpStream::connect(proxy_addr).await?;
+    control.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut method = [0u8; 2];
+    control.read_exact(&mut method).await?;
+    assert_eq!(method, [0x05, 0x00]);
+    control
+        .write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await?;
+    let mut reply = [0u8; 10];
+    control.read_exact(&mut reply).await?;
+    assert_eq!(&reply[..4], &[0x05, 0x00, 0x00, 0x01]);
+    let relay_addr = SocketAddr::from((
+        [reply[4], reply[5], reply[6], reply[7]],
+        u16::from_be_bytes([reply[8], reply[9]]),
+    ));
+
+    // 目标域名按规则转发到回显服务器，回包还原为原始地址
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut packet = vec![0x00, 0x00, 0x00];
+    Address::Domain("udp.test".to_string(), 53).encode(&mut packet);
+    let header_len = packet.len();
+    packet.extend_from_slice(b"ping");
+    client.send_to(&packet, relay_addr).await?;
+    let mut buf = [0u8; 1024];
+    let (n, from) =
+        tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await??;
+    assert_eq!(from, relay_addr);
+    assert_eq!(&buf[..n], &packet[..]);
+    assert_eq!(&buf[header_len..n], b"ping");
+    Ok(())
+}
@@ -0,0 +1,269 @@
+use crate::core::config::{Balance, HealthCheck, Mitm};
+use crate::core::http::{forward_tls, read_status};
+use crate::core::mitm;
+use crate::core::socks::connect_target;
+use anyhow::{Result, anyhow};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+/// 一致性哈希每个上游的虚拟节点数
+const VIRTUAL_NODES: usize = 100;
+
+/// 上游地址及其状态
+#[derive(Debug)]
+struct Upstream {
+    addr: String,
+    healthy: AtomicBool,
+    /// 正在处理的请求数
+    active: AtomicUsize,
+}
+
+/// 转发的上游地址池，按负载均衡策略选择上游
+#[derive(Debug)]
+pub(crate) struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    balance: Balance,
+    /// 一致性哈希使用的请求头，为空时使用客户端IP
+    hash_header: Option<String>,
+    /// 一致性哈希环，(哈希值, 上游序号) 按哈希值排序
+    ring: Vec<(u64, usize)>,
+    /// 轮询位置
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub(crate) fn new(addrs: Vec<String>, balance: Balance, hash_header: Option<String>) -> Self {
+        let mut ring = Vec::new();
+        if balance == Balance::ConsistentHash {
+            for (i, addr) in addrs.iter().enumerate() {
+                for node in 0..VIRTUAL_NODES {
+                    ring.push((fnv1a(format!("{}#{}", addr, node).as_bytes()), i));
+                }
+            }
+            ring.sort_unstable();
+        }
+        Self {
+            upstreams: addrs
+                .into_iter()
+                .map(|addr| Upstream {
+                    addr,
+                    healthy: AtomicBool::new(true),
+                    active: AtomicUsize::new(0),
+                })
+                .collect(),
+            balance,
+            hash_header,
+            ring,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// 一致性哈希的键，请求头不存在时使用客户端IP
+    pub(crate) fn hash_key(&self, headers: &[(String, String)], client_ip: IpAddr) -> String {
+        self.hash_header
+            .as_ref()
+            .and_then(|name| headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)))
+            .map_or_else(|| client_ip.to_string(), |(_, v)| v.clone())
+    }
+
+    /// 按负载均衡策略排序的上游地址，选中的在最前，其余健康的上游依次作为备选
+    ///
+    /// 所有上游都不健康时仍按原顺序返回，由连接结果决定
+    pub(crate) fn candidates(&self, hash_key: &str) -> Vec<&str> {
+        let len = self.upstreams.len();
+        let healthy: Vec<usize> = (0..len)
+            .filter(|i| self.upstreams[*i].healthy.load(Ordering::Relaxed))
+            .collect();
+        if healthy.is_empty() {
+            return self.upstreams.iter().map(|u| u.addr.as_str()).collect();
+        }
+        let selected = match self.balance {
+            Balance::RoundRobin => {
+                healthy[self.next.fetch_add(1, Ordering::Relaxed) % healthy.len()]
+            }
+            Balance::Random => healthy[rand::random_range(0..healthy.len())],
+            Balance::LeastConnections => *healthy
+                .iter()
+                .min_by_key(|i| self.upstreams[**i].active.load(Ordering::Relaxed))
+                .unwrap_or(&healthy[0]),
+            Balance::ConsistentHash => {
+                let hash = fnv1a(hash_key.as_bytes());
+                let start = self.ring.partition_point(|(h, _)| *h < hash);
+                // 顺时针找到第一个健康上游的节点
+                (0..self.ring.len())
+                    .map(|i| self.ring[(start + i) % self.ring.len()].1)
+                    .find(|i| healthy.contains(i))
+                    .unwrap_or(healthy[0])
+            }
+        };
+        std::iter::once(selected)
+            .chain(healthy.into_iter().filter(|i| *i != selected))
+            .map(|i| self.upstreams[i].addr.as_str())
+            .collect()
+    }
+
+    /// 记录发往上游的请求，返回值释放时结束
+    pub(crate) fn acquire(self: &Arc<Self>, addr: &str) -> Option<ActiveRequest> {
+        let index = self.upstreams.iter().position(|u| u.addr == addr)?;
+        self.upstreams[index].active.fetch_add(1, Ordering::Relaxed);
+        Some(ActiveRequest {
+            pool: self.clone(),
+            index,
+        })
+    }
+
+    /// 地址及负载均衡设置相同
+    pub(crate) fn same_as(&self, other: &Self) -> bool {
+        self.balance == other.balance
+            && self.hash_header == other.hash_header
+            && self
+                .upstreams
+                .iter()
+                .map(|u| &u.addr)
+                .eq(other.upstreams.iter().map(|u| &u.addr))
+    }
+
+    /// 启动主动健康检查，地址池释放（规则被替换）后自动停止，设置 mitm 时使用 TLS 连接上游
+    pub(crate) fn spawn_health_check(
+        self: &Arc<Self>,
+        check: HealthCheck,
+        mitm: Option<Mitm>,
+        rule_name: String,
+    ) {
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(health_check(Arc::downgrade(self), check, mitm, rule_name));
+        }
+    }
+}
+
+/// 正在处理的请求，用于最少连接策略
+pub(crate) struct ActiveRequest {
+    pool: Arc<UpstreamPool>,
+    index: usize,
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.pool.upstreams[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn health_check(
+    pool: Weak<UpstreamPool>,
+    check: HealthCheck,
+    mitm: Option<Mitm>,
+    rule_name: String,
+) {
+    let interval = Duration::from_millis(check.interval_ms.max(100));
+    let timeout = Duration::from_millis(check.timeout_ms.max(1));
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(pool) = pool.upgrade() else {
+            break;
+        };
+        let addrs: Vec<String> = pool.upstreams.iter().map(|u| u.addr.clone()).collect();
+        for (i, addr) in addrs.iter().enumerate() {
+            let result =
+                tokio::time::timeout(timeout, probe(addr, check.path.as_deref(), mitm.as_ref()))
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("timed out")));
+            let healthy = result.is_ok();
+            if pool.upstreams[i].healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                match result {
+                    Ok(()) => info!("[{}] Upstream {} is healthy", rule_name, addr),
+                    Err(e) => warn!("[{}] Upstream {} is unhealthy: {}", rule_name, addr, e),
+                }
+            }
+        }
+    }
+}
+
+/// 检查上游，设置路径时发送HTTP请求并要求 2xx/3xx 响应，否则只检查TCP连接及 TLS 握手
+async fn probe(addr: &str, path: Option<&str>, mitm: Option<&Mitm>) -> Result<()> {
+    let stream = connect_target(addr).await?;
+    let mut stream = match mitm {
+        Some(mitm) => mitm::Upstream::Tls(Box::new(
+            mitm::connect(stream, &forward_tls(mitm, addr)).await?,
+        )),
+        None => mitm::Upstream::Plain(stream),
+    };
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: proxy-forward-health-check\r\nConnection: close\r\n\r\n",
+        path, addr
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let status = read_status(&mut stream).await?;
+    if !(200..400).contains(&status) {
+        return Err(anyhow!("status {}", status));
+    }
+    Ok(())
+}
+
+/// FNV-1a 64位哈希，结果不随进程变化
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[test]
+fn test_balance() {
+    let addrs = || vec!["a:1".to_string(), "b:1".to_string(), "c:1".to_string()];
+    let pool = Arc::new(UpstreamPool::new(addrs(), Balance::RoundRobin, None));
+    let picks: Vec<&str> = (0..4).map(|_| pool.candidates("")[0]).collect();
+    assert_eq!(picks, ["a:1", "b:1", "c:1", "a:1"]);
+    pool.upstreams[1].healthy.store(false, Ordering::Relaxed);
+    assert_eq!(pool.candidates(""), ["a:1", "c:1"]);
+    assert_eq!(pool.candidates(""), ["c:1", "a:1"]);
+
+    let pool = Arc::new(UpstreamPool::new(addrs(), Balance::LeastConnections, None));
+    let _a = pool.acquire("a:1").unwrap();
+    let b = pool.acquire("b:1").unwrap();
+    assert_eq!(pool.candidates("")[0], "c:1");
+    let _c = pool.acquire("c:1").unwrap();
+    drop(b);
+    assert_eq!(pool.candidates("")[0], "b:1");
+
+    let pool = UpstreamPool::new(addrs(), Balance::ConsistentHash, Some("X-User".to_string()));
+    let headers = [("x-user".to_string(), "alice".to_string())];
+    let key = pool.hash_key(&headers, "10.0.0.1".parse().unwrap());
+    assert_eq!(key, "alice");
+    let first = pool.candidates(&key)[0].to_string();
+    assert!((0..10).all(|_| pool.candidates(&key)[0] == first));
+    // 选中的上游不健康时换到其他上游，恢复后回到原来的上游
+    let index = pool.upstreams.iter().position(|u| u.addr == first).unwrap();
+    pool.upstreams[index]
+        .healthy
+        .store(false, Ordering::Relaxed);
+    assert_ne!(pool.candidates(&key)[0], first);
+    pool.upstreams[index].healthy.store(true, Ordering::Relaxed);
+    assert_eq!(pool.candidates(&key)[0], first);
+    assert_eq!(pool.hash_key(&[], "10.0.0.1".parse().unwrap()), "10.0.0.1");
+}
+
+#[tokio::test]
+async fn test_probe() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    use tokio::io::AsyncReadExt;
+    tokio::spawn(async move {
+        for status in ["200 OK", "503 Service Unavailable"] {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+            socket.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    assert!(probe(&addr, Some("/health"), None).await.is_ok());
+    assert!(probe(&addr, Some("/health"), None).await.is_err());
+}
@@ -1,7 +1,7 @@
 use crate::core::config::AppConfig;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use tracing::{error, info};
 
 mod core;
@@ -12,28 +12,93 @@ async fn main() -> anyhow::Result<()> {
     libs::logs::init_default()?;
     let config = AppConfig::init().expect("读取配置文件失败");
 
-    let listener = TcpListener::bind(config.listen_addr.as_str()).await?;
-    info!("SOCKS5 proxy listening on {}", config.listen_addr);
-
     let mut vec = Vec::new();
-    for r in config.rules {
-        let rule = core::route::RouteRule::new(
-            r.matcher.addr.as_str(),
-            r.matcher.path_prefix.as_str(),
-            r.forward.addr.as_str(),
-            r.forward.path_prefix.as_str(),
-        );
-        vec.push(rule);
+    for r in &config.rules {
+        vec.push(core::route::RouteRule::try_from(r)?);
     }
-    let rules = Arc::new(RwLock::new(vec));
+    let rules = Arc::new(RwLock::new(Vec::new()));
     let route_engine = Arc::new(core::route::RouteEngine { rules });
+    route_engine.update_rules(vec).await;
+    if std::env::args().nth(1).as_deref() == Some("explain") {
+        return explain(&route_engine, std::env::args().skip(2).collect()).await;
+    }
+
+    let listener = TcpListener::bind(config.listen_addr.as_str()).await?;
+    info!("SOCKS/HTTP proxy listening on {}", config.listen_addr);
+    let users = Arc::new(config.users.clone());
+    let admin_enabled = config.admin.enabled;
+    let config = Arc::new(Mutex::new(config));
+    let engine = route_engine.clone();
+    let watched = config.clone();
+    tokio::spawn(async move {
+        if let Err(e) = core::reload::watch(engine, watched).await {
+            error!("Config reload stopped: {}", e);
+        }
+    });
+    if admin_enabled {
+        let engine = route_engine.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = core::admin::serve(engine, config).await {
+                error!("Admin API stopped: {}", e);
+            }
+        });
+    }
     loop {
         let (socket, _) = listener.accept().await?;
         let engine = route_engine.clone();
+        let users = users.clone();
         tokio::spawn(async move {
-            if let Err(e) = core::socks::handle_client(socket, engine).await {
+            // 根据第一个字节区分SOCKS和HTTP代理请求
+            let mut first = [0u8; 1];
+            let result = match socket.peek(&mut first).await {
+                Ok(0) => Ok(()),
+                Ok(_) if first[0] == 0x04 || first[0] == 0x05 => {
+                    core::socks::handle_client(socket, engine, users).await
+                }
+                Ok(_) => core::http_proxy::handle_client(socket, engine, users).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
                 error!("Error handling client: {}", e);
             }
         });
     }
 }
+
+/// 说明请求的路由匹配结果
+///
+/// `proxy-forward explain [-X METHOD] [-H 'Name: value']... http://host/path`
+async fn explain(route_engine: &core::route::RouteEngine, args: Vec<String>) -> anyhow::Result<()> {
+    let usage = "usage: explain [-X METHOD] [-H 'Name: value']... http://host/path";
+    let mut method = "GET".to_string();
+    let mut headers = Vec::new();
+    let mut url = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-X" => method = args.next().ok_or(anyhow::anyhow!(usage))?,
+            "-H" => {
+                let header = args.next().ok_or(anyhow::anyhow!(usage))?;
+                let (name, value) = header.split_once(':').ok_or(anyhow::anyhow!(usage))?;
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+            _ => url = Some(arg),
+        }
+    }
+    let url = url.ok_or(anyhow::anyhow!(usage))?;
+    let (authority, path) =
+        core::http_proxy::parse_absolute_uri(&url).ok_or(anyhow::anyhow!(usage))?;
+    let host = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("host"))
+        .map_or(authority, |(_, v)| v.as_str());
+    let request = core::route::RouteRequest {
+        method: &method,
+        host,
+        path: &path,
+        headers: &headers,
+    };
+    println!("{}", route_engine.explain(&request).await);
+    Ok(())
+}